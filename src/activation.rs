@@ -0,0 +1,74 @@
+use std::os::fd::RawFd;
+use std::os::raw::c_void;
+
+use crate::syscall;
+
+/// First of the sockets passed by the service manager, the others follow.
+const LISTEN_FDS_START: RawFd = 3;
+
+/// A socket passed by the service manager, with its name from
+/// `LISTEN_FDNAMES` (`unknown` if none was given).
+pub struct Activated {
+    pub fd: RawFd,
+    pub name: String,
+}
+
+/// Sockets passed by a service manager such as systemd, following its
+/// socket activation protocol: `LISTEN_FDS` of them from fd 3 on, meant for
+/// this process if `LISTEN_PID` is its id. None were passed if there are
+/// none of them, so the server binds its own.
+pub(crate) fn listen_fds() -> std::io::Result<Option<Vec<Activated>>> {
+    let pid = std::env::var("LISTEN_PID")
+        .ok()
+        .and_then(|pid| pid.parse().ok());
+    if pid != Some(std::process::id()) {
+        return Ok(None);
+    }
+    let count: RawFd = std::env::var("LISTEN_FDS")
+        .ok()
+        .and_then(|count| count.parse().ok())
+        .filter(|count| *count >= 0)
+        .ok_or_else(|| invalid("LISTEN_FDS is not a count".to_owned()))?;
+    if count == 0 {
+        return Ok(None);
+    }
+    let names = std::env::var("LISTEN_FDNAMES").unwrap_or_default();
+    let mut names = names.split(':');
+    let mut sockets = Vec::new();
+    for fd in LISTEN_FDS_START..LISTEN_FDS_START + count {
+        if !is_listening(fd) {
+            return Err(invalid(format!("fd {fd} is not a listening socket")));
+        }
+        // not to be leaked to anything started later
+        let flags = syscall!(fcntl(fd, libc::F_GETFD))?;
+        syscall!(fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC))?;
+        sockets.push(Activated {
+            fd,
+            name: names
+                .next()
+                .filter(|name| !name.is_empty())
+                .unwrap_or("unknown")
+                .to_owned(),
+        });
+    }
+    Ok(Some(sockets))
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn is_listening(fd: RawFd) -> bool {
+    let mut accepting: libc::c_int = 0;
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let got = syscall!(getsockopt(
+        fd,
+        libc::SOL_SOCKET,
+        libc::SO_ACCEPTCONN,
+        (&raw mut accepting).cast::<c_void>(),
+        &raw mut len
+    ));
+    // fails if it is not open or not a socket at all
+    got.is_ok_and(|_| accepting != 0)
+}
+
+fn invalid(msg: String) -> std::io::Error {
+    std::io::Error::new(std::io::ErrorKind::InvalidInput, msg)
+}
@@ -0,0 +1,125 @@
+use std::os::fd::RawFd;
+use std::str::FromStr;
+
+use crate::reactor::{State, Token};
+use crate::syscall;
+use crate::uring::IoUring;
+
+/// Readiness notification mechanism under a [`Reactor`](crate::reactor::Reactor).
+/// Interest is one-shot: once reported, a registration stays quiet until
+/// `modify` re-arms it.
+pub(crate) trait Backend {
+    fn add(&mut self, fd: RawFd, events: u32, token: Token) -> std::io::Result<()>;
+
+    fn modify(&mut self, fd: RawFd, events: u32, token: Token) -> std::io::Result<()>;
+
+    /// Forgets `fd`, which the reactor closes right afterwards.
+    fn remove(&mut self, fd: RawFd, token: Token) -> std::io::Result<()>;
+
+    /// Waits up to `timeout` milliseconds, or forever for -1, and fills
+    /// `events` with the registrations that became ready.
+    fn wait(&mut self, events: &mut Vec<(Token, State)>, timeout: i32) -> std::io::Result<()>;
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+pub enum BackendKind {
+    #[default]
+    Epoll,
+    IoUring,
+}
+
+impl FromStr for BackendKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "epoll" => Ok(BackendKind::Epoll),
+            "io_uring" => Ok(BackendKind::IoUring),
+            _ => Err(format!("unknown backend {s}")),
+        }
+    }
+}
+
+impl BackendKind {
+    pub(crate) fn create(self) -> std::io::Result<Box<dyn Backend>> {
+        Ok(match self {
+            BackendKind::Epoll => Box::new(Epoll::new()?),
+            BackendKind::IoUring => Box::new(IoUring::new(MAX_EVENTS)?),
+        })
+    }
+}
+
+/// Most events handled by one turn of the loop.
+const MAX_EVENTS: u32 = 1024;
+
+pub(crate) struct Epoll {
+    fd: RawFd,
+    events: Vec<libc::epoll_event>,
+}
+
+impl Epoll {
+    fn new() -> std::io::Result<Self> {
+        let fd = syscall!(epoll_create1(libc::EPOLL_CLOEXEC))?;
+        Ok(Self {
+            fd,
+            events: Vec::with_capacity(MAX_EVENTS as usize),
+        })
+    }
+
+    fn ctl(&self, op: i32, fd: RawFd, events: u32, token: Token) -> std::io::Result<()> {
+        let mut event = libc::epoll_event {
+            events,
+            u64: token.to_u64(),
+        };
+        syscall!(epoll_ctl(self.fd, op, fd, &raw mut event))?;
+        Ok(())
+    }
+}
+
+impl Backend for Epoll {
+    fn add(&mut self, fd: RawFd, events: u32, token: Token) -> std::io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_ADD, fd, events, token)
+    }
+
+    fn modify(&mut self, fd: RawFd, events: u32, token: Token) -> std::io::Result<()> {
+        self.ctl(libc::EPOLL_CTL_MOD, fd, events, token)
+    }
+
+    fn remove(&mut self, fd: RawFd, _token: Token) -> std::io::Result<()> {
+        syscall!(epoll_ctl(
+            self.fd,
+            libc::EPOLL_CTL_DEL,
+            fd,
+            std::ptr::null_mut()
+        ))?;
+        Ok(())
+    }
+
+    fn wait(&mut self, events: &mut Vec<(Token, State)>, timeout: i32) -> std::io::Result<()> {
+        #[allow(clippy::cast_possible_wrap)]
+        let res = syscall!(epoll_wait(
+            self.fd,
+            self.events.as_mut_ptr(),
+            MAX_EVENTS as i32,
+            timeout
+        ))?;
+
+        #[allow(clippy::cast_sign_loss)]
+        unsafe {
+            self.events.set_len(res as usize);
+        };
+
+        events.extend(
+            self.events
+                .iter()
+                .map(|ev| (Token::from_u64(ev.u64), State::new(ev.events))),
+        );
+        Ok(())
+    }
+}
+
+impl Drop for Epoll {
+    fn drop(&mut self) {
+        let _ = unsafe { libc::close(self.fd) };
+    }
+}
@@ -0,0 +1,700 @@
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::OnceLock;
+use std::time::Duration;
+
+use crate::backend::BackendKind;
+use crate::reactor::FaultPolicy;
+
+/// How much is logged, each level including the ones before it.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    /// Only what went wrong.
+    Error,
+    /// Also what the server does as a whole, such as statistics.
+    #[default]
+    Info,
+    /// Also every connection and request.
+    Debug,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            _ => Err(format!("unknown log level {s}")),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Config {
+    /// Worker threads; read at startup only, like the settings up to
+    /// `listeners`.
+    pub workers: usize,
+    pub backend: BackendKind,
+    pub fault_policy: FaultPolicy,
+    /// Pending connections each listening socket has room for, unless its
+    /// listener says otherwise.
+    pub backlog: libc::c_int,
+    /// Where stdout and stderr go instead, appended to.
+    pub log_file: Option<PathBuf>,
+    /// Addresses to serve.
+    pub listeners: Vec<ListenerSpec>,
+    pub log_level: LogLevel,
+    /// Connections each worker serves at once; it stops accepting beyond.
+    pub max_connections: usize,
+    /// Requests served over one keep-alive connection before it is closed.
+    pub max_requests_per_connection: usize,
+    /// Where request bodies are streamed to.
+    pub upload_dir: PathBuf,
+    /// Largest request head in bytes, answered with 431 beyond that.
+    pub max_header_size: usize,
+    /// Most header fields in a request head, answered with 431 beyond that.
+    pub max_header_count: usize,
+    /// Largest request body in bytes, answered with 413 beyond that.
+    pub max_body_size: usize,
+    /// Time allowed to send a complete request head.
+    pub header_timeout: Duration,
+    /// Time allowed between two reads of a request body.
+    pub body_timeout: Duration,
+    /// Time a kept-alive connection may wait for its next request.
+    pub keep_alive_timeout: Duration,
+    /// Time allowed between two writes of a response.
+    pub write_timeout: Duration,
+    /// Time the connections in flight get to finish once a shutdown starts.
+    pub drain_timeout: Duration,
+    /// Routes to serve, each with the name of a built-in handler.
+    pub routes: Vec<RouteSpec>,
+    /// Directories whose files are served as they are.
+    pub statics: Vec<StaticSpec>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            workers: 1,
+            backend: BackendKind::default(),
+            fault_policy: FaultPolicy::default(),
+            backlog: libc::SOMAXCONN,
+            log_file: None,
+            listeners: vec![ListenerSpec::new(ListenAddr::Tcp(SocketAddr::from((
+                [127, 0, 0, 1],
+                8000,
+            ))))],
+            log_level: LogLevel::default(),
+            max_connections: 1024,
+            max_requests_per_connection: 1000,
+            upload_dir: std::env::temp_dir(),
+            max_header_size: 16 * 1024,
+            max_header_count: 100,
+            max_body_size: 100 * 1024 * 1024,
+            header_timeout: Duration::from_secs(10),
+            body_timeout: Duration::from_secs(30),
+            keep_alive_timeout: Duration::from_secs(15),
+            write_timeout: Duration::from_secs(30),
+            drain_timeout: Duration::from_secs(30),
+            routes: vec![
+                RouteSpec::new("GET", "/", "hello"),
+                RouteSpec::new("GET", "/hello/:name", "greet"),
+                RouteSpec::new("POST", "/upload", "upload"),
+            ],
+            statics: Vec::new(),
+        }
+    }
+}
+
+/// An address to serve, from `--listen` or a `[listener]` section.
+#[derive(Clone, Debug)]
+pub struct ListenerSpec {
+    pub address: ListenAddr,
+    /// Overrides [`Config::backlog`] for this listener.
+    pub backlog: Option<libc::c_int>,
+    /// Limits, timeouts and routes of its own, if its section sets any;
+    /// those of the configuration apply otherwise.
+    pub config: Option<Config>,
+}
+
+impl ListenerSpec {
+    pub(crate) fn new(address: ListenAddr) -> Self {
+        Self {
+            address,
+            backlog: None,
+            config: None,
+        }
+    }
+}
+
+/// Where a listener accepts connections, written `ADDR:PORT` or
+/// `unix:PATH`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ListenAddr {
+    Tcp(SocketAddr),
+    Unix(PathBuf),
+}
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.strip_prefix("unix:") {
+            Some("") => Err(format!("missing path in {s}")),
+            Some(path) => Ok(ListenAddr::Unix(PathBuf::from(path))),
+            None => s
+                .parse()
+                .map(ListenAddr::Tcp)
+                .map_err(|_| format!("expected ADDR:PORT or unix:PATH, got {s}")),
+        }
+    }
+}
+
+/// A route of the configuration, written `METHOD PATTERN HANDLER`.
+#[derive(Clone, Debug)]
+pub struct RouteSpec {
+    pub method: String,
+    pub pattern: String,
+    pub handler: String,
+}
+
+impl RouteSpec {
+    fn new(method: &str, pattern: &str, handler: &str) -> Self {
+        Self {
+            method: method.to_owned(),
+            pattern: pattern.to_owned(),
+            handler: handler.to_owned(),
+        }
+    }
+}
+
+impl FromStr for RouteSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split_whitespace();
+        let (Some(method), Some(pattern), Some(handler), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(format!("expected METHOD PATTERN HANDLER, got {s}"));
+        };
+        let wildcard_inside = pattern
+            .split('/')
+            .rev()
+            .skip(1)
+            .any(|segment| segment.starts_with('*'));
+        if wildcard_inside {
+            return Err(format!("wildcard must be the last segment of {pattern}"));
+        }
+        Ok(Self::new(method, pattern, handler))
+    }
+}
+
+/// Files under `root` served below `prefix`, written `PREFIX DIR`.
+#[derive(Clone, Debug)]
+pub struct StaticSpec {
+    pub prefix: String,
+    pub root: PathBuf,
+}
+
+impl FromStr for StaticSpec {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (prefix, root) = s
+            .split_once(char::is_whitespace)
+            .map(|(prefix, root)| (prefix, root.trim()))
+            .filter(|(prefix, _)| prefix.starts_with('/'))
+            .ok_or_else(|| format!("expected /PREFIX DIR, got {s}"))?;
+        // the files are matched by a wildcard after the prefix
+        if prefix
+            .split('/')
+            .any(|segment| segment.starts_with(['*', ':']))
+        {
+            return Err(format!("prefix {prefix} must be a plain path"));
+        }
+        Ok(Self {
+            prefix: prefix.to_owned(),
+            root: PathBuf::from(root),
+        })
+    }
+}
+
+/// What is wrong in a line of a configuration file, with the part of the
+/// line at fault to tell its column.
+struct Invalid<'a>(&'a str, String);
+
+/// Routes and static roots of a part of a configuration file, which
+/// replace the others unless there are none.
+#[derive(Default)]
+struct Lists {
+    routes: Vec<RouteSpec>,
+    statics: Vec<StaticSpec>,
+}
+
+impl Lists {
+    fn apply(self, config: &mut Config) {
+        if !self.routes.is_empty() {
+            config.routes = self.routes;
+        }
+        if !self.statics.is_empty() {
+            config.statics = self.statics;
+        }
+    }
+}
+
+/// A `[listener]` section, from the line it starts on.
+struct Section {
+    line: usize,
+    address: Option<ListenAddr>,
+    backlog: Option<libc::c_int>,
+    /// The settings above the section, with those of the section on top.
+    config: Config,
+    lists: Lists,
+    /// Whether the section sets any of `config`.
+    own: bool,
+}
+
+impl Config {
+    /// Whether every connection and request is logged.
+    pub(crate) fn verbose(&self) -> bool {
+        self.log_level >= LogLevel::Debug
+    }
+
+    /// Settings of the connections accepted on `address`: those of its
+    /// listener, or these ones for a socket that no listener names, such as
+    /// one passed by the service manager.
+    pub(crate) fn site(&self, address: &ListenAddr) -> &Config {
+        self.listeners
+            .iter()
+            .find(|listener| listener.address == *address)
+            .and_then(|listener| listener.config.as_ref())
+            .unwrap_or(self)
+    }
+
+    /// Whether `self` and `other` name the same addresses, in any order.
+    fn same_listeners(&self, other: &Config) -> bool {
+        let has = |config: &Config, address: &ListenAddr| {
+            config.listeners.iter().any(|l| l.address == *address)
+        };
+        self.listeners.iter().all(|l| has(other, &l.address))
+            && other.listeners.iter().all(|l| has(self, &l.address))
+    }
+
+    /// Applies the lines of a configuration file on top of `self`. Blank
+    /// lines and lines starting with `#` are skipped. The others are
+    /// `key = value` lines, with the keys named like the command line flags,
+    /// up to the first `[listener]` section. Each section holds the
+    /// `address` and `backlog` of a listener, and the limits, timeouts and
+    /// routes it has of its own. The routes, static roots and listeners of
+    /// the file replace the others.
+    fn apply(&mut self, text: &str, is_handler: fn(&str) -> bool) -> Result<(), String> {
+        let mut lists = Some(Lists::default());
+        let mut sections: Vec<Section> = Vec::new();
+        for (number, line) in text.lines().enumerate() {
+            let trimmed = line.trim();
+            if trimmed.is_empty() || trimmed.starts_with('#') {
+                continue;
+            }
+            let applied = if let Some(header) = trimmed.strip_prefix('[') {
+                section(header, trimmed).map(|()| {
+                    // sections start from what is above them
+                    if let Some(lists) = lists.take() {
+                        lists.apply(self);
+                    }
+                    sections.push(Section::new(number + 1, self));
+                })
+            } else {
+                split(trimmed).and_then(|(key, value)| match (sections.last_mut(), &mut lists) {
+                    (Some(section), _) => section.apply_line(key, value, is_handler),
+                    (None, Some(lists)) => self.apply_line(key, value, is_handler, lists),
+                    (None, None) => unreachable!("lists are taken by the first section"),
+                })
+            };
+            applied.map_err(|Invalid(at, msg)| {
+                let offset = at.as_ptr() as usize - line.as_ptr() as usize;
+                let column = line[..offset].chars().count() + 1;
+                format!("line {}, column {column}: {msg}", number + 1)
+            })?;
+        }
+        if let Some(lists) = lists {
+            lists.apply(self);
+        }
+        if !sections.is_empty() {
+            self.listeners = sections
+                .into_iter()
+                .map(Section::finish)
+                .collect::<Result<_, _>>()?;
+        }
+        Ok(())
+    }
+
+    /// Sets one of the settings that are not lists.
+    fn set(&mut self, key: &str, value: &str) -> Result<(), String> {
+        if matches!(key, "route" | "static") {
+            return Err(format!("unknown key {key}"));
+        }
+        self.apply_line(key, value, |_| false, &mut Lists::default())
+            .map_err(|Invalid(_, msg)| msg)
+    }
+
+    fn apply_line<'a>(
+        &mut self,
+        key: &'a str,
+        value: &'a str,
+        is_handler: fn(&str) -> bool,
+        lists: &mut Lists,
+    ) -> Result<(), Invalid<'a>> {
+        let secs = |value: &str| value.parse().map(Duration::from_secs);
+        match key {
+            "workers" => self.workers = parse(key, value, str::parse)?,
+            "backend" => self.backend = parse(key, value, str::parse)?,
+            "on-error" => self.fault_policy = parse(key, value, str::parse)?,
+            "backlog" => self.backlog = parse(key, value, str::parse)?,
+            "log-level" => self.log_level = parse(key, value, str::parse)?,
+            "log-file" => self.log_file = Some(PathBuf::from(value)),
+            "verbose" => {
+                if parse(key, value, str::parse)? {
+                    self.log_level = LogLevel::Debug;
+                }
+            }
+            "max-connections" => self.max_connections = parse(key, value, str::parse)?,
+            "max-requests" => self.max_requests_per_connection = parse(key, value, str::parse)?,
+            "upload-dir" => self.upload_dir = PathBuf::from(value),
+            "max-header-size" => self.max_header_size = parse(key, value, str::parse)?,
+            "max-header-count" => self.max_header_count = parse(key, value, str::parse)?,
+            "max-body-size" => self.max_body_size = parse(key, value, str::parse)?,
+            "header-timeout" => self.header_timeout = parse(key, value, secs)?,
+            "body-timeout" => self.body_timeout = parse(key, value, secs)?,
+            "keep-alive-timeout" => self.keep_alive_timeout = parse(key, value, secs)?,
+            "write-timeout" => self.write_timeout = parse(key, value, secs)?,
+            "drain-timeout" => self.drain_timeout = parse(key, value, secs)?,
+            "route" => {
+                let route: RouteSpec = value.parse().map_err(|e| Invalid(value, e))?;
+                if !is_handler(&route.handler) {
+                    let at = &value[value.rfind(&route.handler).unwrap_or_default()..];
+                    return Err(Invalid(at, format!("unknown handler {}", route.handler)));
+                }
+                lists.routes.push(route);
+            }
+            "static" => lists
+                .statics
+                .push(value.parse().map_err(|e| Invalid(value, e))?),
+            _ => return Err(Invalid(key, format!("unknown key {key}"))),
+        }
+        Ok(())
+    }
+}
+
+impl Section {
+    fn new(line: usize, config: &Config) -> Self {
+        Self {
+            line,
+            address: None,
+            backlog: None,
+            config: Config {
+                listeners: Vec::new(),
+                ..config.clone()
+            },
+            lists: Lists::default(),
+            own: false,
+        }
+    }
+
+    fn apply_line<'a>(
+        &mut self,
+        key: &'a str,
+        value: &'a str,
+        is_handler: fn(&str) -> bool,
+    ) -> Result<(), Invalid<'a>> {
+        match key {
+            "address" => self.address = Some(parse(key, value, str::parse)?),
+            "backlog" => self.backlog = Some(parse(key, value, str::parse)?),
+            // shared by all listeners
+            "workers" | "backend" | "on-error" | "log-level" | "log-file" | "verbose" => {
+                return Err(Invalid(key, format!("{key} cannot be set for a listener")));
+            }
+            _ => {
+                self.own = true;
+                self.config
+                    .apply_line(key, value, is_handler, &mut self.lists)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn finish(self) -> Result<ListenerSpec, String> {
+        let Some(address) = self.address else {
+            return Err(format!(
+                "line {}, column 1: listener without an address",
+                self.line
+            ));
+        };
+        let mut config = self.config;
+        self.lists.apply(&mut config);
+        Ok(ListenerSpec {
+            address,
+            backlog: self.backlog,
+            config: self.own.then_some(config),
+        })
+    }
+}
+
+/// Checks the `header` of a section, the part of `line` after its `[`.
+fn section<'a>(header: &'a str, line: &'a str) -> Result<(), Invalid<'a>> {
+    match header.strip_suffix(']').map(str::trim) {
+        Some("listener") => Ok(()),
+        Some(name) => Err(Invalid(name, format!("unknown section {name}"))),
+        None => Err(Invalid(line, format!("expected [SECTION], got {line}"))),
+    }
+}
+
+fn split(line: &str) -> Result<(&str, &str), Invalid<'_>> {
+    line.split_once('=')
+        .map(|(key, value)| (key.trim(), value.trim()))
+        .ok_or_else(|| Invalid(line, format!("expected key = value, got {line}")))
+}
+
+fn parse<'a, T, E: std::fmt::Display>(
+    key: &str,
+    value: &'a str,
+    f: impl Fn(&str) -> Result<T, E>,
+) -> Result<T, Invalid<'a>> {
+    f(value).map_err(|e| Invalid(value, format!("invalid value for {key}: {e}")))
+}
+
+/// Settings given on the command line, which take precedence over those of
+/// the configuration file.
+#[derive(Default)]
+pub struct Overrides {
+    /// `key = value` pairs, named like the keys of the file.
+    settings: Vec<(String, String)>,
+    /// Replace those of the file unless there are none.
+    listeners: Vec<ListenerSpec>,
+}
+
+impl Overrides {
+    /// Sets `key` to `value`, which is checked right away.
+    pub(crate) fn set(&mut self, key: &str, value: String) -> Result<(), String> {
+        Config::default().set(key, &value)?;
+        self.settings.push((key.to_owned(), value));
+        Ok(())
+    }
+
+    pub(crate) fn listen(&mut self, address: ListenAddr) {
+        self.listeners.push(ListenerSpec::new(address));
+    }
+
+    /// Applies them on top of `config`, its listeners included.
+    fn apply(&self, config: &mut Config) -> Result<(), String> {
+        if !self.listeners.is_empty() {
+            config.listeners.clone_from(&self.listeners);
+        }
+        for (key, value) in &self.settings {
+            config.set(key, value)?;
+            for listener in &mut config.listeners {
+                // it falls back to the one just set
+                if key == "backlog" {
+                    listener.backlog = None;
+                }
+                if let Some(config) = &mut listener.config {
+                    config.set(key, value)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Configuration that can be read again while running: the defaults with
+/// the configuration file, if any, and then the command line on top.
+pub struct Reloadable {
+    overrides: Overrides,
+    path: Option<PathBuf>,
+    /// Tells the handlers that routes may name.
+    is_handler: fn(&str) -> bool,
+    /// The first configuration loaded, whose listeners are the ones bound.
+    first: OnceLock<Config>,
+}
+
+impl Reloadable {
+    pub(crate) fn new(
+        overrides: Overrides,
+        path: Option<PathBuf>,
+        is_handler: fn(&str) -> bool,
+    ) -> Self {
+        Self {
+            overrides,
+            path,
+            is_handler,
+            first: OnceLock::new(),
+        }
+    }
+
+    /// Reads the configuration file again. Once one has been loaded, a file
+    /// that adds or removes listeners is refused, as they only change with a
+    /// restart or an upgrade.
+    pub(crate) fn load(&self) -> std::io::Result<Config> {
+        let mut config = Config::default();
+        if let Some(path) = &self.path {
+            let text = std::fs::read_to_string(path)
+                .map_err(|e| std::io::Error::new(e.kind(), format!("{}: {e}", path.display())))?;
+            config
+                .apply(&text, self.is_handler)
+                .map_err(|e| invalid(path, &e))?;
+        }
+        self.overrides
+            .apply(&mut config)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let first = self.first.get_or_init(|| config.clone());
+        if !first.same_listeners(&config) {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                "the listeners changed, which takes a restart or an upgrade",
+            ));
+        }
+        Ok(config)
+    }
+}
+
+fn invalid(path: &Path, msg: &str) -> std::io::Error {
+    std::io::Error::new(
+        std::io::ErrorKind::InvalidData,
+        format!("{}: {msg}", path.display()),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn is_handler(name: &str) -> bool {
+        name == "hello"
+    }
+
+    fn apply(text: &str) -> Result<Config, String> {
+        let mut config = Config::default();
+        config.apply(text, is_handler).map(|()| config)
+    }
+
+    #[test]
+    fn settings_and_listeners() {
+        let config = apply(
+            "# comment\n\
+             max-body-size = 1024\n\
+             log-file = /var/log/server.log\n\
+             route = GET / hello\n\
+             \n\
+             [listener]\n\
+             address = 127.0.0.1:8001\n\
+             \n\
+             [ listener ]\n\
+             address = unix:/tmp/admin.sock\n\
+             backlog = 16\n\
+             max-body-size = 10\n\
+             route = GET /admin hello\n",
+        )
+        .unwrap();
+        assert_eq!(config.max_body_size, 1024);
+        assert_eq!(
+            config.log_file.as_deref(),
+            Some(Path::new("/var/log/server.log"))
+        );
+        assert_eq!(config.routes.len(), 1);
+        let [public, admin] = &config.listeners[..] else {
+            panic!("expected two listeners");
+        };
+        assert!(public.config.is_none());
+        assert_eq!(
+            admin.address,
+            ListenAddr::Unix(PathBuf::from("/tmp/admin.sock"))
+        );
+        assert_eq!(admin.backlog, Some(16));
+        let own = config.site(&admin.address);
+        assert_eq!(own.max_body_size, 10);
+        assert_eq!(own.routes[0].pattern, "/admin");
+        assert_eq!(config.site(&public.address).max_body_size, 1024);
+    }
+
+    #[test]
+    fn errors_tell_line_and_column() {
+        for (text, error) in [
+            (
+                "workers = 1\nmax-body-size = lots\n",
+                "line 2, column 17: invalid value for max-body-size: invalid digit found in string",
+            ),
+            (
+                "  backend =  kqueue",
+                "line 1, column 14: invalid value for backend: unknown backend kqueue",
+            ),
+            ("timeout = 5", "line 1, column 1: unknown key timeout"),
+            (
+                "workers",
+                "line 1, column 1: expected key = value, got workers",
+            ),
+            ("[server]", "line 1, column 2: unknown section server"),
+            (
+                "[listener",
+                "line 1, column 1: expected [SECTION], got [listener",
+            ),
+            (
+                "route = GET / hello\nroute = POST /upload upload\n",
+                "line 2, column 22: unknown handler upload",
+            ),
+            (
+                "\n[listener]\nbacklog = 1\n",
+                "line 2, column 1: listener without an address",
+            ),
+            (
+                "[listener]\naddress = 127.0.0.1:80\nworkers = 2\n",
+                "line 3, column 1: workers cannot be set for a listener",
+            ),
+            (
+                "[listener]\naddress = 127.0.0.1:80\nlog-file = /tmp/log\n",
+                "line 3, column 1: log-file cannot be set for a listener",
+            ),
+            (
+                "static = /*x /tmp\n",
+                "line 1, column 10: prefix /*x must be a plain path",
+            ),
+            (
+                "static = /files/:name /tmp\n",
+                "line 1, column 10: prefix /files/:name must be a plain path",
+            ),
+            (
+                "[listener]\naddress = localhost\n",
+                "line 2, column 11: invalid value for address: expected ADDR:PORT or unix:PATH, got localhost",
+            ),
+        ] {
+            assert_eq!(apply(text).err().as_deref(), Some(error), "{text:?}");
+        }
+    }
+
+    #[test]
+    fn command_line_over_file() {
+        let mut config = apply(
+            "log-level = error\n\
+             max-body-size = 1024\n\
+             [listener]\n\
+             address = 127.0.0.1:8001\n\
+             backlog = 128\n\
+             max-body-size = 10\n",
+        )
+        .unwrap();
+        let mut overrides = Overrides::default();
+        overrides.set("backlog", "7".to_owned()).unwrap();
+        overrides.set("log-level", "debug".to_owned()).unwrap();
+        overrides.set("max-body-size", "5".to_owned()).unwrap();
+        assert!(overrides.set("max-body-size", "x".to_owned()).is_err());
+        assert!(overrides.set("route", "GET / hello".to_owned()).is_err());
+        overrides.apply(&mut config).unwrap();
+        assert_eq!(config.log_level, LogLevel::Debug);
+        assert_eq!(config.max_body_size, 5);
+        assert_eq!(config.site(&config.listeners[0].address).max_body_size, 5);
+        assert_eq!(config.backlog, 7);
+        assert_eq!(config.listeners[0].backlog, None);
+    }
+}
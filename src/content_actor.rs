@@ -4,14 +4,16 @@ use std::mem::MaybeUninit;
 use std::os::fd::RawFd;
 use std::rc::Rc;
 
+use crate::http::{self, Framing, Request};
 use crate::{log, syscall};
-use crate::reactor::{State, EventReceiver, InterestAction, InterestActions, Reactor, READ};
+use crate::reactor::{State, EventReceiver, InterestAction, InterestActions, Reactor, Token, READ};
+use crate::signal::SignalEvent;
 
 use crate::request_context::Handle as ReqHandle;
 use crate::request_context::Message as ReqMessage;
 
 pub enum Message {
-    ContentLengthRequest { req: String, sender: RawFd },
+    BodyFramingRequest { req: Request, sender: Token },
 }
 
 struct Actor {
@@ -33,33 +35,26 @@ impl Actor {
         }
     }
 
-    fn parse_and_set_content_length(&self, data: &str) -> usize {
-        let mut result = 0;
-        let content_length_slice = "content-length: ";
-        let content_length_sz = content_length_slice.len();
-        if data.contains("HTTP") {
-            if let Some(content_length) = data.lines().find(|l| {
-                l.len() > content_length_sz
-                    && l[..content_length_sz].eq_ignore_ascii_case(content_length_slice)
-            }) {
-                result = content_length[content_length_sz..]
-                    .parse::<usize>()
-                    .expect("content-length is valid");
-                if self.verbose {
-                    log(&format!("set content length: {result} bytes"));
-                }
+    fn parse_body_framing(&self, req: &Request) -> Result<Framing, http::Error> {
+        let result = http::framing(req)?;
+        if self.verbose {
+            match result {
+                Framing::Length(0) => {}
+                Framing::Length(n) => log(&format!("set content length: {n} bytes")),
+                Framing::Chunked => log("set chunked body"),
             }
         }
-        result
+        Ok(result)
     }
 
     fn handle_message(&self, msg: Message) -> std::io::Result<()> {
         match msg {
-            Message::ContentLengthRequest { req, sender } => {
-                let content_length = self.parse_and_set_content_length(&req);
-                self.req_handle.enqueue(ReqMessage::ContentLengthResponse {
+            Message::BodyFramingRequest { req, sender } => {
+                let framing = self.parse_body_framing(&req);
+                self.req_handle.enqueue(ReqMessage::BodyFramingResponse {
                     receiver: sender,
-                    content_length,
+                    req,
+                    framing,
                 })
             }
         }
@@ -71,6 +66,7 @@ impl EventReceiver for Actor {
         &mut self,
         ready_to: State,
         fd: RawFd,
+        token: Token,
         new_actions: &mut InterestActions,
     ) -> std::io::Result<()> {
         debug_assert!(ready_to.read());
@@ -79,7 +75,18 @@ impl EventReceiver for Actor {
         for msg in self.ctr_queue.borrow_mut().drain(..) {
             self.handle_message(msg)?;
         }
-        new_actions.add(InterestAction::Modify(fd, READ));
+        new_actions.add(InterestAction::Modify(token, READ));
+        Ok(())
+    }
+
+    fn on_signal(
+        &mut self,
+        event: &SignalEvent,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if let SignalEvent::Reload(config) = event {
+            self.verbose = config.verbose();
+        }
         Ok(())
     }
 }
@@ -93,7 +100,10 @@ pub struct Handle {
 impl Handle {
     pub(crate) fn new() -> std::io::Result<Self> {
         let ctr_queue = Rc::new(RefCell::new(VecDeque::new()));
-        let efd = syscall!(eventfd(0, libc::EFD_SEMAPHORE | libc::EFD_NONBLOCK))?;
+        let efd = syscall!(eventfd(
+            0,
+            libc::EFD_SEMAPHORE | libc::EFD_NONBLOCK | libc::EFD_CLOEXEC
+        ))?;
 
         Ok(Self { efd, ctr_queue })
     }
@@ -0,0 +1,829 @@
+use std::borrow::Cow;
+use std::fmt;
+use std::fs::File;
+use std::ops::Range;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum Version {
+    Http10,
+    #[default]
+    Http11,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Error {
+    Encoding,
+    RequestLine,
+    Version,
+    HeaderLine,
+    ContentLength,
+    TransferEncoding,
+    Chunk,
+    HeadTooLarge,
+    BodyTooLarge,
+    Timeout,
+}
+
+impl Error {
+    /// Status code of the response that reports the error.
+    #[must_use]
+    pub fn status(self) -> u16 {
+        match self {
+            Error::TransferEncoding => 501,
+            Error::HeadTooLarge => 431,
+            Error::BodyTooLarge => 413,
+            Error::Timeout => 408,
+            _ => 400,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let msg = match self {
+            Error::Encoding => "request head is not valid UTF-8",
+            Error::RequestLine => "malformed request line",
+            Error::Version => "unsupported HTTP version",
+            Error::HeaderLine => "malformed header line",
+            Error::ContentLength => "invalid content-length",
+            Error::TransferEncoding => "unsupported transfer-encoding",
+            Error::Chunk => "malformed chunked body",
+            Error::HeadTooLarge => "request head is too large",
+            Error::BodyTooLarge => "request body is too large",
+            Error::Timeout => "request timed out",
+        };
+        f.write_str(msg)
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Parsed request head. Method, target and headers are kept as ranges into
+/// a single owned copy of the head, so a `Request` can be reused without
+/// reallocating once it has grown to fit the usual traffic.
+#[derive(Default)]
+pub struct Request {
+    head: String,
+    method: Range<usize>,
+    target: Range<usize>,
+    version: Version,
+    headers: Vec<(Range<usize>, Range<usize>)>,
+}
+
+impl Request {
+    #[must_use]
+    pub fn method(&self) -> &str {
+        &self.head[self.method.clone()]
+    }
+
+    #[must_use]
+    pub fn target(&self) -> &str {
+        &self.head[self.target.clone()]
+    }
+
+    /// The target without its query string.
+    #[must_use]
+    pub fn path(&self) -> &str {
+        let target = self.target();
+        target.split_once('?').map_or(target, |(path, _)| path)
+    }
+
+    #[must_use]
+    pub fn version(&self) -> Version {
+        self.version
+    }
+
+    /// Returns the first header named `name`, compared case-insensitively.
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v)
+    }
+
+    pub fn headers(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.headers
+            .iter()
+            .map(|(n, v)| (&self.head[n.clone()], &self.head[v.clone()]))
+    }
+
+    /// Tells whether the client wants the connection to stay open after the
+    /// response: the default for HTTP/1.1 and opt-in for HTTP/1.0.
+    #[must_use]
+    pub fn keep_alive(&self) -> bool {
+        let has_option = |option: &str| {
+            self.headers()
+                .filter(|(n, _)| n.eq_ignore_ascii_case("connection"))
+                .flat_map(|(_, v)| v.split(','))
+                .any(|o| o.trim().eq_ignore_ascii_case(option))
+        };
+        match self.version {
+            Version::Http11 => !has_option("close"),
+            Version::Http10 => has_option("keep-alive"),
+        }
+    }
+
+    fn parse_head(&mut self, head: &[u8]) -> Result<(), Error> {
+        let head = std::str::from_utf8(head).map_err(|_| Error::Encoding)?;
+        self.head.clear();
+        self.head.push_str(head);
+        self.method = 0..0;
+        self.target = 0..0;
+        self.headers.clear();
+
+        let mut lines = Lines::new(head);
+        let (start, line) = lines.next().ok_or(Error::RequestLine)?;
+        self.parse_request_line(start, line)?;
+
+        for (start, line) in lines {
+            if line.is_empty() {
+                break;
+            }
+            self.parse_header_line(start, line)?;
+        }
+        Ok(())
+    }
+
+    fn parse_request_line(&mut self, start: usize, line: &str) -> Result<(), Error> {
+        let mut parts = line.split(' ');
+        let (Some(method), Some(target), Some(version), None) =
+            (parts.next(), parts.next(), parts.next(), parts.next())
+        else {
+            return Err(Error::RequestLine);
+        };
+        if !is_token(method) || target.is_empty() || target.bytes().any(|b| b <= b' ' || b == 0x7f)
+        {
+            return Err(Error::RequestLine);
+        }
+        self.version = match version {
+            "HTTP/1.1" => Version::Http11,
+            "HTTP/1.0" => Version::Http10,
+            v if v.starts_with("HTTP/") => return Err(Error::Version),
+            _ => return Err(Error::RequestLine),
+        };
+        self.method = start..start + method.len();
+        let target_start = self.method.end + 1;
+        self.target = target_start..target_start + target.len();
+        Ok(())
+    }
+
+    fn parse_header_line(&mut self, start: usize, line: &str) -> Result<(), Error> {
+        // obsolete line folding is rejected as allowed by RFC 9112, section 5.2
+        let (name, value) = line.split_once(':').ok_or(Error::HeaderLine)?;
+        if !is_token(name) {
+            return Err(Error::HeaderLine);
+        }
+        let lead = value.len() - value.trim_start_matches([' ', '\t']).len();
+        let trimmed = value[lead..].trim_end_matches([' ', '\t']);
+        if trimmed
+            .bytes()
+            .any(|b| (b < b' ' && b != b'\t') || b == 0x7f)
+        {
+            return Err(Error::HeaderLine);
+        }
+        let value_start = start + name.len() + 1 + lead;
+        self.headers.push((
+            start..start + name.len(),
+            value_start..value_start + trimmed.len(),
+        ));
+        Ok(())
+    }
+}
+
+/// Response produced by a handler. `content-length` and `connection` are
+/// added when the response is written and must not be set here.
+pub struct Response {
+    status: u16,
+    headers: Vec<(String, String)>,
+    body: Payload,
+}
+
+/// Body of a [`Response`]: bytes in memory, or the first `len` bytes of a
+/// file, which are sent with `sendfile` without being read into memory.
+pub enum Payload {
+    Bytes(Cow<'static, [u8]>),
+    File(File, usize),
+}
+
+impl Payload {
+    #[must_use]
+    pub fn len(&self) -> usize {
+        match self {
+            Self::Bytes(bytes) => bytes.len(),
+            Self::File(_, len) => *len,
+        }
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl Default for Payload {
+    fn default() -> Self {
+        Self::Bytes(Cow::Borrowed(&[]))
+    }
+}
+
+impl Response {
+    #[must_use]
+    pub fn new(status: u16) -> Self {
+        Self {
+            status,
+            headers: Vec::new(),
+            body: Payload::default(),
+        }
+    }
+
+    #[must_use]
+    pub fn with_header(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.headers.push((name.into(), value.into()));
+        self
+    }
+
+    #[must_use]
+    pub fn with_body(mut self, body: impl Into<Vec<u8>>) -> Self {
+        self.body = Payload::Bytes(Cow::Owned(body.into()));
+        self
+    }
+
+    /// Sets a body that is sent without being copied.
+    #[must_use]
+    pub fn with_static_body(mut self, body: &'static [u8]) -> Self {
+        self.body = Payload::Bytes(Cow::Borrowed(body));
+        self
+    }
+
+    /// Sets a body of `len` bytes streamed from the start of `file`.
+    #[must_use]
+    pub fn with_file(mut self, file: File, len: usize) -> Self {
+        self.body = Payload::File(file, len);
+        self
+    }
+
+    #[must_use]
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    /// Serializes the status line and headers into `out`.
+    pub fn write_head(&self, out: &mut Vec<u8>, keep_alive: bool) {
+        use std::io::Write;
+
+        let _ = write!(
+            out,
+            "HTTP/1.1 {} {}\r\n",
+            self.status,
+            reason_phrase(self.status)
+        );
+        for (name, value) in &self.headers {
+            let _ = write!(out, "{name}: {value}\r\n");
+        }
+        let connection = if keep_alive { "keep-alive" } else { "close" };
+        let _ = write!(
+            out,
+            "content-length: {}\r\nconnection: {connection}\r\n\r\n",
+            self.body.len()
+        );
+    }
+
+    #[must_use]
+    pub fn into_body(self) -> Payload {
+        self.body
+    }
+}
+
+#[must_use]
+pub fn reason_phrase(status: u16) -> &'static str {
+    match status {
+        100 => "Continue",
+        200 => "OK",
+        201 => "Created",
+        204 => "No Content",
+        301 => "Moved Permanently",
+        302 => "Found",
+        304 => "Not Modified",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        405 => "Method Not Allowed",
+        408 => "Request Timeout",
+        411 => "Length Required",
+        413 => "Content Too Large",
+        414 => "URI Too Long",
+        431 => "Request Header Fields Too Large",
+        500 => "Internal Server Error",
+        501 => "Not Implemented",
+        503 => "Service Unavailable",
+        _ => "",
+    }
+}
+
+/// Iterates over lines together with their offsets, accepting both CRLF and
+/// bare LF as terminators.
+struct Lines<'a> {
+    rest: &'a str,
+    pos: usize,
+}
+
+impl<'a> Lines<'a> {
+    fn new(s: &'a str) -> Self {
+        Self { rest: s, pos: 0 }
+    }
+}
+
+impl<'a> Iterator for Lines<'a> {
+    type Item = (usize, &'a str);
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.rest.is_empty() {
+            return None;
+        }
+        let start = self.pos;
+        let (line, rest) = self.rest.split_once('\n').unwrap_or((self.rest, ""));
+        self.pos += line.len() + 1;
+        self.rest = rest;
+        Some((start, line.strip_suffix('\r').unwrap_or(line)))
+    }
+}
+
+fn is_token(s: &str) -> bool {
+    !s.is_empty()
+        && s.bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b))
+}
+
+/// Finds request heads in a byte stream that arrives in arbitrary pieces.
+#[derive(Default)]
+pub struct Parser {
+    scanned: usize,
+}
+
+impl Parser {
+    /// Parses the request head at the start of `buf` into `req` once it is
+    /// complete, returning the number of bytes it occupies. Returns `None`
+    /// if more data is needed; the bytes already searched are remembered, so
+    /// the next call with a longer `buf` carries on where this one stopped.
+    ///
+    /// # Errors
+    ///
+    /// Returns an error if the head is complete but malformed.
+    pub fn parse(&mut self, buf: &[u8], req: &mut Request) -> Result<Option<usize>, Error> {
+        // RFC 9112, section 2.2: ignore empty lines preceding the request line
+        let skip = buf
+            .iter()
+            .position(|b| *b != b'\r' && *b != b'\n')
+            .unwrap_or(buf.len());
+        let Some(end) = self.find_end(buf, skip) else {
+            return Ok(None);
+        };
+        self.scanned = 0;
+        req.parse_head(&buf[skip..end])?;
+        Ok(Some(end))
+    }
+
+    fn find_end(&mut self, buf: &[u8], skip: usize) -> Option<usize> {
+        for i in self.scanned.max(skip)..buf.len() {
+            if buf[i] != b'\n' {
+                continue;
+            }
+            match &buf[i + 1..] {
+                [b'\n', ..] => return Some(i + 2),
+                [b'\r', b'\n', ..] => return Some(i + 3),
+                _ => {}
+            }
+        }
+        // the terminator may straddle the next read
+        self.scanned = buf.len().saturating_sub(2);
+        None
+    }
+}
+
+/// Returns the body length announced by `content-length`. Repeated headers
+/// are accepted only if they all agree.
+///
+/// # Errors
+///
+/// Returns [`Error::ContentLength`] if a value is not a number or the values differ.
+pub fn content_length(req: &Request) -> Result<usize, Error> {
+    let mut result = None;
+    for (_, value) in req
+        .headers()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("content-length"))
+    {
+        if value.is_empty() || !value.bytes().all(|b| b.is_ascii_digit()) {
+            return Err(Error::ContentLength);
+        }
+        let length = value.parse::<usize>().map_err(|_| Error::ContentLength)?;
+        if result.is_some_and(|r| r != length) {
+            return Err(Error::ContentLength);
+        }
+        result = Some(length);
+    }
+    Ok(result.unwrap_or(0))
+}
+
+/// How the end of a request body is determined.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Framing {
+    Length(usize),
+    Chunked,
+}
+
+/// Works out the body framing from `transfer-encoding` and `content-length`.
+///
+/// # Errors
+///
+/// Returns an error for codings other than a final `chunked`, for requests
+/// that carry both headers (a request smuggling vector) and for invalid
+/// lengths.
+pub fn framing(req: &Request) -> Result<Framing, Error> {
+    let mut codings = req
+        .headers()
+        .filter(|(n, _)| n.eq_ignore_ascii_case("transfer-encoding"))
+        .flat_map(|(_, v)| v.split(','))
+        .map(str::trim);
+    let coding = match (codings.next(), codings.next()) {
+        (None, _) => return content_length(req).map(Framing::Length),
+        (Some(coding), None) => coding,
+        // no coding besides chunked is supported for requests
+        (Some(_), Some(_)) => return Err(Error::TransferEncoding),
+    };
+    if !coding.eq_ignore_ascii_case("chunked") || req.version == Version::Http10 {
+        return Err(Error::TransferEncoding);
+    }
+    if req.header("content-length").is_some() {
+        return Err(Error::ContentLength);
+    }
+    Ok(Framing::Chunked)
+}
+
+/// Longest chunk-size line, with its extensions, or trailer line.
+const MAX_CHUNK_LINE: usize = 4096;
+
+#[derive(Clone, Copy)]
+enum ChunkState {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+}
+
+/// Decodes a chunked body in place: chunk data is moved to the front of the
+/// buffer as it arrives, where [`ChunkedDecoder::take`] hands it out.
+pub struct ChunkedDecoder {
+    state: ChunkState,
+    /// Bytes of the encoded body consumed so far.
+    read: usize,
+    /// Bytes of the decoded body at the start of the buffer.
+    written: usize,
+    /// Bytes of the unfinished line after `read` already searched for its
+    /// end.
+    scanned: usize,
+}
+
+impl Default for ChunkedDecoder {
+    fn default() -> Self {
+        Self {
+            state: ChunkState::Size,
+            read: 0,
+            written: 0,
+            scanned: 0,
+        }
+    }
+}
+
+impl ChunkedDecoder {
+    /// Decodes as much of `buf` as possible and tells whether the last chunk
+    /// and the trailers are in. Anything past the body belongs to the next
+    /// request.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`Error::Chunk`] if the encoding is malformed or a chunk-size
+    /// or trailer line is too long.
+    pub fn decode(&mut self, buf: &mut [u8]) -> Result<bool, Error> {
+        loop {
+            match self.state {
+                ChunkState::Size => {
+                    let Some(line) = self.line(buf)? else {
+                        return Ok(false);
+                    };
+                    let size = parse_chunk_size(line)?;
+                    self.state = if size == 0 {
+                        ChunkState::Trailers
+                    } else {
+                        ChunkState::Data(size)
+                    };
+                }
+                ChunkState::Data(remaining) => {
+                    let available = (buf.len() - self.read).min(remaining);
+                    if available == 0 {
+                        return Ok(false);
+                    }
+                    buf.copy_within(self.read..self.read + available, self.written);
+                    self.read += available;
+                    self.written += available;
+                    self.state = if available == remaining {
+                        ChunkState::DataEnd
+                    } else {
+                        ChunkState::Data(remaining - available)
+                    };
+                }
+                ChunkState::DataEnd => {
+                    let Some(line) = self.line(buf)? else {
+                        return Ok(false);
+                    };
+                    if !line.is_empty() {
+                        return Err(Error::Chunk);
+                    }
+                    self.state = ChunkState::Size;
+                }
+                ChunkState::Trailers => {
+                    let Some(line) = self.line(buf)? else {
+                        return Ok(false);
+                    };
+                    if line.is_empty() {
+                        return Ok(true);
+                    }
+                    // trailer fields are accepted but not merged into the head
+                    let valid = line
+                        .iter()
+                        .position(|b| *b == b':')
+                        .is_some_and(|i| std::str::from_utf8(&line[..i]).is_ok_and(is_token));
+                    if !valid {
+                        return Err(Error::Chunk);
+                    }
+                }
+            }
+        }
+    }
+
+    /// Returns the length of the decoded data at the start of the buffer and
+    /// the number of encoded bytes consumed. The caller takes the data and
+    /// drains the consumed bytes, so decoding resumes at the buffer start.
+    pub fn take(&mut self) -> (usize, usize) {
+        let result = (self.written, self.read);
+        self.written = 0;
+        self.read = 0;
+        result
+    }
+
+    /// Consumes the next line of `buf`, without its terminator. Returns
+    /// `None` if it is not complete yet; the bytes already searched are
+    /// remembered, as in [`Parser::parse`].
+    fn line<'a>(&mut self, buf: &'a [u8]) -> Result<Option<&'a [u8]>, Error> {
+        let start = self.read;
+        let Some(len) = buf[start + self.scanned..]
+            .iter()
+            .position(|b| *b == b'\n')
+            .map(|i| self.scanned + i)
+        else {
+            self.scanned = buf.len() - start;
+            if self.scanned > MAX_CHUNK_LINE {
+                return Err(Error::Chunk);
+            }
+            return Ok(None);
+        };
+        if len > MAX_CHUNK_LINE {
+            return Err(Error::Chunk);
+        }
+        self.scanned = 0;
+        self.read = start + len + 1;
+        let line = &buf[start..start + len];
+        Ok(Some(line.strip_suffix(b"\r").unwrap_or(line)))
+    }
+}
+
+fn parse_chunk_size(line: &[u8]) -> Result<usize, Error> {
+    // chunk extensions after `;` carry no meaning for this server
+    let size = line
+        .iter()
+        .position(|b| *b == b';')
+        .map_or(line, |i| &line[..i]);
+    let size = std::str::from_utf8(size)
+        .map_err(|_| Error::Chunk)?
+        .trim_end_matches([' ', '\t']);
+    if size.is_empty() || !size.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(Error::Chunk);
+    }
+    usize::from_str_radix(size, 16).map_err(|_| Error::Chunk)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Parses `head` fed in pieces of `step` bytes and returns the request
+    /// with the length of its head.
+    fn parse(head: &[u8], step: usize) -> Result<(Request, usize), Error> {
+        let mut parser = Parser::default();
+        let mut req = Request::default();
+        let mut buf = Vec::new();
+        for piece in head.chunks(step) {
+            buf.extend_from_slice(piece);
+            if let Some(len) = parser.parse(&buf, &mut req)? {
+                return Ok((req, len));
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    fn head(text: &str) -> Result<Request, Error> {
+        parse(text.as_bytes(), text.len()).map(|(req, _)| req)
+    }
+
+    #[test]
+    fn heads_split_anywhere() {
+        let text = b"POST /a?b=c HTTP/1.1\r\nHost: x\r\nX-Empty:\r\nX-Pad: \t v \t\r\n\r\nbody";
+        for step in 1..=text.len() {
+            let (req, len) = parse(text, step).unwrap();
+            assert_eq!(len, text.len() - 4, "step {step}");
+            assert_eq!(req.method(), "POST");
+            assert_eq!(req.path(), "/a");
+            assert_eq!(req.header("host"), Some("x"));
+            assert_eq!(req.header("x-empty"), Some(""));
+            assert_eq!(req.header("X-PAD"), Some("v"));
+        }
+    }
+
+    #[test]
+    fn terminator_split_across_reads() {
+        let text = b"GET / HTTP/1.1\r\nHost: x\r\n\r\n";
+        for split in 1..text.len() {
+            let mut parser = Parser::default();
+            let mut req = Request::default();
+            assert_eq!(
+                parser.parse(&text[..split], &mut req),
+                Ok(None),
+                "split {split}"
+            );
+            assert_eq!(parser.parse(text, &mut req), Ok(Some(text.len())));
+        }
+        // bare LF terminators too
+        let bare = b"GET / HTTP/1.1\nHost: x\n\n";
+        assert_eq!(parse(bare, 1).unwrap().1, bare.len());
+    }
+
+    #[test]
+    fn leading_blank_lines() {
+        let text = b"\r\n\n\r\nGET / HTTP/1.0\r\n\r\n";
+        for step in 1..=text.len() {
+            let (req, len) = parse(text, step).unwrap();
+            assert_eq!(len, text.len());
+            assert_eq!(req.version(), Version::Http10);
+        }
+    }
+
+    #[test]
+    fn malformed_heads() {
+        for (text, error) in [
+            // obsolete line folding
+            (
+                "GET / HTTP/1.1\r\nX-A: 1\r\n  folded\r\n\r\n",
+                Error::HeaderLine,
+            ),
+            (
+                "GET / HTTP/1.1\r\nX-A: 1\r\n\tx: 2\r\n\r\n",
+                Error::HeaderLine,
+            ),
+            ("GET / HTTP/1.1\r\nX A: 1\r\n\r\n", Error::HeaderLine),
+            ("GET / HTTP/1.1\r\nX-A : 1\r\n\r\n", Error::HeaderLine),
+            ("GET / HTTP/1.1\r\nX-A: a\x01b\r\n\r\n", Error::HeaderLine),
+            ("GET  / HTTP/1.1\r\n\r\n", Error::RequestLine),
+            ("GET / HTTP/1.1 x\r\n\r\n", Error::RequestLine),
+            ("GET / HTTP/2.0\r\n\r\n", Error::Version),
+            ("GET / http/1.1\r\n\r\n", Error::RequestLine),
+        ] {
+            assert_eq!(head(text).err(), Some(error), "{text:?}");
+        }
+        let invalid = parse(b"GET /\xff HTTP/1.1\r\n\r\n", 32);
+        assert_eq!(invalid.err(), Some(Error::Encoding));
+    }
+
+    #[test]
+    fn body_framing() {
+        let framing = |headers: &str, version: &str| {
+            framing(&head(&format!("POST / {version}\r\n{headers}\r\n")).unwrap())
+        };
+        let http11 = |headers: &str| framing(headers, "HTTP/1.1");
+        assert_eq!(http11(""), Ok(Framing::Length(0)));
+        assert_eq!(http11("Content-Length: 5\r\n"), Ok(Framing::Length(5)));
+        assert_eq!(
+            http11("Content-Length: 5\r\ncontent-length: 5\r\n"),
+            Ok(Framing::Length(5))
+        );
+        assert_eq!(
+            http11("Content-Length: 5\r\nContent-Length: 6\r\n"),
+            Err(Error::ContentLength)
+        );
+        for value in ["", "-1", "+5", "5, 5", "0x5", "99999999999999999999999"] {
+            assert_eq!(
+                http11(&format!("Content-Length: {value}\r\n")),
+                Err(Error::ContentLength),
+                "{value:?}"
+            );
+        }
+        assert_eq!(
+            http11("Transfer-Encoding: chunked\r\n"),
+            Ok(Framing::Chunked)
+        );
+        assert_eq!(
+            http11("Transfer-Encoding: chunked\r\nContent-Length: 5\r\n"),
+            Err(Error::ContentLength)
+        );
+        assert_eq!(
+            http11("Content-Length: 5\r\nTransfer-Encoding: chunked\r\n"),
+            Err(Error::ContentLength)
+        );
+        assert_eq!(
+            http11("Transfer-Encoding: gzip, chunked\r\n"),
+            Err(Error::TransferEncoding)
+        );
+        assert_eq!(
+            http11("Transfer-Encoding: chunked\r\nTransfer-Encoding: chunked\r\n"),
+            Err(Error::TransferEncoding)
+        );
+        assert_eq!(
+            framing("Transfer-Encoding: chunked\r\n", "HTTP/1.0"),
+            Err(Error::TransferEncoding)
+        );
+    }
+
+    /// Feeds `body` to a decoder in pieces of `step` bytes, the way a
+    /// connection does, and returns the decoded data once it is complete.
+    fn decode(body: &[u8], step: usize) -> Result<Vec<u8>, Error> {
+        let mut decoder = ChunkedDecoder::default();
+        let mut buf = Vec::new();
+        let mut out = Vec::new();
+        for piece in body.chunks(step) {
+            buf.extend_from_slice(piece);
+            let done = decoder.decode(&mut buf);
+            let (len, consumed) = decoder.take();
+            out.extend_from_slice(&buf[..len]);
+            buf.drain(..consumed);
+            if done? {
+                return Ok(out);
+            }
+        }
+        Err(Error::Timeout)
+    }
+
+    const BODY: &[u8] = b"5\r\nhello\r\n7;name=value\r\n, world\r\n0\r\nx-sum: 1\r\n\r\n";
+
+    #[test]
+    fn chunks_split_anywhere() {
+        for step in 1..=BODY.len() {
+            assert_eq!(decode(BODY, step).unwrap(), b"hello, world", "step {step}");
+        }
+    }
+
+    #[test]
+    fn chunk_extensions_and_trailers() {
+        let body = b"3;a;b=\"c\"\r\nabc\r\n0;last\r\nx-a: 1\r\nx-b: 2\r\n\r\n";
+        assert_eq!(decode(body, body.len()).unwrap(), b"abc");
+        assert_eq!(decode(b"0\n\n", 4).unwrap(), b"");
+        assert_eq!(decode(b"0\r\nno colon\r\n\r\n", 16), Err(Error::Chunk));
+    }
+
+    #[test]
+    fn keeps_the_next_request() {
+        let mut decoder = ChunkedDecoder::default();
+        let mut buf = b"1\r\na\r\n0\r\n\r\nGET / HTTP/1.1\r\n".to_vec();
+        assert_eq!(decoder.decode(&mut buf), Ok(true));
+        let (len, consumed) = decoder.take();
+        assert_eq!(&buf[..len], b"a");
+        assert_eq!(&buf[consumed..], b"GET / HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn bad_chunk_sizes() {
+        for body in [
+            &b"\r\n"[..],
+            b"x\r\n",
+            b"-1\r\n",
+            b" 1\r\n",
+            b"0x1\r\n",
+            b"ffffffffffffffffff\r\n",
+            b"1\r\nab\r\n",
+        ] {
+            assert_eq!(decode(body, body.len()), Err(Error::Chunk), "{body:?}");
+        }
+    }
+
+    #[test]
+    fn long_lines() {
+        let mut body = b"1;".to_vec();
+        body.resize(MAX_CHUNK_LINE + 1, b'x');
+        // refused before the line ends, however it arrives
+        assert_eq!(decode(&body, 1), Err(Error::Chunk));
+        assert_eq!(decode(&body, body.len()), Err(Error::Chunk));
+        body.extend_from_slice(b"\r\n");
+        assert_eq!(decode(&body, body.len()), Err(Error::Chunk));
+
+        let mut trailer = b"0\r\nx-a: ".to_vec();
+        trailer.resize(MAX_CHUNK_LINE + 8, b'x');
+        assert_eq!(decode(&trailer, 64), Err(Error::Chunk));
+    }
+}
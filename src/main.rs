@@ -1,14 +1,38 @@
+#![allow(clippy::macro_metavars_in_unsafe)]
+
 use std::cell::RefCell;
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::sync::atomic::{AtomicU8, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
 
+pub mod activation;
+pub mod backend;
+pub mod config;
 pub mod content_actor;
+pub mod http;
+pub mod multipart;
+pub mod out_buffer;
 pub mod reactor;
 pub mod request;
 pub mod request_context;
+pub mod router;
+pub mod shutdown;
 pub mod signal;
 pub mod timer;
+pub mod timer_queue;
+pub mod upgrade;
+mod upload;
+pub mod uring;
 
+use crate::config::{Config, ListenAddr, LogLevel, Overrides, Reloadable};
+use crate::http::{Request, Response};
 use crate::reactor::{EventReceiver, InterestAction, InterestActions, Reactor, READ};
+use crate::router::{Body, Params, Router};
+use crate::shutdown::Shutdown;
+use crate::signal::{Hub, Signals};
 
 #[macro_export]
 macro_rules! syscall {
@@ -23,48 +47,833 @@ macro_rules! syscall {
     }};
 }
 
+/// The [`LogLevel`] in effect, shared by all workers.
+static LOG_LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+
+fn set_log_level(level: LogLevel) {
+    LOG_LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+/// The file stdout and stderr are sent to, if any.
+static LOG_FILE: Mutex<Option<PathBuf>> = Mutex::new(None);
+
+/// Sends stdout and stderr to the end of `path` from now on.
+fn set_log_file(path: &Path) -> std::io::Result<()> {
+    let mut log_file = LOG_FILE
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    redirect_log(path)?;
+    *log_file = Some(path.to_owned());
+    Ok(())
+}
+
+/// Opens the log file again, so that a rotated one is let go of and the
+/// log goes on in a new file at its path.
+fn reopen_log() -> std::io::Result<()> {
+    let log_file = LOG_FILE
+        .lock()
+        .unwrap_or_else(std::sync::PoisonError::into_inner);
+    match &*log_file {
+        Some(path) => redirect_log(path),
+        None => Ok(()),
+    }
+}
+
+fn redirect_log(path: &Path) -> std::io::Result<()> {
+    let file = std::fs::File::options()
+        .append(true)
+        .create(true)
+        .open(path)?;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        syscall!(dup2(file.as_raw_fd(), fd))?;
+    }
+    Ok(())
+}
+
+/// Logs what the server does, unless only errors are to be logged.
 #[cold]
 fn log(msg: &str) {
-    println!("{msg}");
+    if LOG_LEVEL.load(Ordering::Relaxed) >= LogLevel::Info as u8 {
+        println!("{msg}");
+    }
 }
 
-fn main() -> std::io::Result<()> {
-    let mut verbose = false;
+/// Logs what went wrong, whatever the level.
+#[cold]
+fn log_error(msg: &str) {
+    eprintln!("{msg}");
+}
+
+fn parse_value<T>(flag: &str, value: Option<String>) -> Result<T, String>
+where
+    T: std::str::FromStr,
+    T::Err: std::fmt::Display,
+{
+    value
+        .ok_or_else(|| format!("missing value for {flag}"))?
+        .parse()
+        .map_err(|e| format!("invalid value for {flag}: {e}"))
+}
+
+fn hello(_req: &Request, _params: &Params<'_>, _body: &Body<'_>) -> Response {
+    Response::new(200)
+        .with_header("content-type", "text/html")
+        .with_static_body(b"Hello")
+}
+
+fn greet(_req: &Request, params: &Params<'_>, _body: &Body<'_>) -> Response {
+    let name = params.get("name").unwrap_or_default();
+    Response::new(200)
+        .with_header("content-type", "text/plain")
+        .with_body(format!("Hello, {name}"))
+}
+
+fn upload(_req: &Request, _params: &Params<'_>, body: &Body<'_>) -> Response {
+    let size = |path: &Path| std::fs::metadata(path).map_or(0, |m| m.len());
+    let msg = match body {
+        Body::Empty => "received 0 bytes".to_owned(),
+        Body::File(path) => format!("received {} bytes", size(path)),
+        Body::Form(form) => form
+            .files()
+            .iter()
+            .map(|f| format!("received {} ({} bytes)\n", f.filename, size(&f.path)))
+            .chain(form.fields().map(|(n, v)| format!("{n} = {v}\n")))
+            .collect(),
+    };
+    Response::new(200)
+        .with_header("content-type", "text/plain")
+        .with_body(msg)
+}
+
+/// Answers with the file at `path` below `root`.
+fn serve_file(root: &Path, path: &str) -> Response {
+    // nothing but plain names, which cannot lead out of the root
+    if path
+        .split('/')
+        .any(|name| name.is_empty() || name == "." || name == "..")
+    {
+        return Response::new(404);
+    }
+    // O_NONBLOCK keeps a FIFO from stalling the loop in open; regular
+    // files ignore it
+    let file = match std::fs::File::options()
+        .read(true)
+        .custom_flags(libc::O_NONBLOCK)
+        .open(root.join(path))
+    {
+        Ok(file) => file,
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => return Response::new(403),
+        Err(_) => return Response::new(404),
+    };
+    // anything but a regular file, a directory say, is not served
+    match file.metadata() {
+        Ok(meta) if meta.is_file() => match usize::try_from(meta.len()) {
+            Ok(len) => Response::new(200)
+                .with_header("content-type", content_type(path))
+                .with_file(file, len),
+            Err(_) => Response::new(500),
+        },
+        _ => Response::new(404),
+    }
+}
+
+fn content_type(path: &str) -> &'static str {
+    match path.rsplit_once('.').map(|(_, extension)| extension) {
+        Some("html" | "htm") => "text/html",
+        Some("css") => "text/css",
+        Some("js") => "text/javascript",
+        Some("json") => "application/json",
+        Some("txt") => "text/plain",
+        Some("svg") => "image/svg+xml",
+        Some("png") => "image/png",
+        Some("jpg" | "jpeg") => "image/jpeg",
+        _ => "application/octet-stream",
+    }
+}
+
+type HandlerFn = fn(&Request, &Params<'_>, &Body<'_>) -> Response;
+
+/// Handlers that routes of the configuration can name.
+fn handler(name: &str) -> Option<HandlerFn> {
+    match name {
+        "hello" => Some(hello),
+        "greet" => Some(greet),
+        "upload" => Some(upload),
+        _ => None,
+    }
+}
 
-    let args = std::env::args().skip(1);
-    for arg in args {
+fn is_handler(name: &str) -> bool {
+    handler(name).is_some()
+}
+
+fn routes(config: &Config) -> Router {
+    let mut router = Router::new();
+    for route in &config.routes {
+        if let Some(handler) = handler(&route.handler) {
+            // the configuration was checked, so this is not expected
+            if let Err(e) = router.add(&route.method, &route.pattern, handler) {
+                log_error(&format!("skipping route: {e}"));
+            }
+        }
+    }
+    for spec in &config.statics {
+        let root = spec.root.clone();
+        let pattern = format!("{}/*path", spec.prefix.trim_end_matches('/'));
+        let added = router.get(
+            &pattern,
+            move |_req: &Request, params: &Params<'_>, _body: &Body<'_>| {
+                serve_file(&root, params.get("path").unwrap_or_default())
+            },
+        );
+        if let Err(e) = added {
+            log_error(&format!("skipping static root: {e}"));
+        }
+    }
+    router
+}
+
+/// Settings shared by the worker threads, each of which runs its own
+/// reactor with its own listening socket, request context and content actor.
+struct Worker<'a> {
+    config: Config,
+    /// Listening sockets of each worker.
+    sockets: &'a [Vec<RawFd>],
+    shutdown: &'a Shutdown,
+    signals: &'a Signals,
+    hub: &'a Arc<Hub>,
+    reloadable: &'a Arc<Reloadable>,
+    /// Workers still setting up their reactor.
+    starting: &'a AtomicUsize,
+}
+
+impl Worker<'_> {
+    /// Serves until shutdown. A worker that fails stops the others too.
+    fn run(&self, index: usize) -> std::io::Result<()> {
+        let result = self.serve(index);
+        if result.is_err() {
+            let _ = self.shutdown.force();
+        }
+        result
+    }
+
+    fn serve(&self, index: usize) -> std::io::Result<()> {
+        let verbose = self.config.verbose();
+        let mut reactor =
+            Reactor::new(self.config.backend)?.with_fault_policy(self.config.fault_policy);
+        let content_handle = content_actor::Handle::new()?;
+        let req_handle = request_context::Handle::new()?;
+        let addresses = self.sockets[index]
+            .iter()
+            .map(|&fd| request::local_addr(fd))
+            .collect::<std::io::Result<_>>()?;
+        let req_actor = req_handle.bind(
+            &mut reactor,
+            &self.config,
+            addresses,
+            routes,
+            content_handle.clone(),
+        )?;
+        content_handle.bind(&mut reactor, verbose, req_handle.clone())?;
+
+        let mut accepts = Vec::new();
+        for (site, &fd) in self.sockets[index].iter().enumerate() {
+            let listener = request::Listener::new(verbose, req_actor.clone(), fd, site)?;
+            let fd = listener.raw_fd();
+            accepts.push(reactor.add_interest(fd, READ, Rc::new(RefCell::new(listener)))?);
+        }
+
+        let shutdown_listener = Rc::new(RefCell::new(shutdown::Listener::new(
+            verbose,
+            self.shutdown,
+            accepts,
+            req_handle,
+            self.config.drain_timeout,
+        )));
+        reactor.add_interest(self.shutdown.drain_fd(), READ, shutdown_listener.clone())?;
+        reactor.add_interest(self.shutdown.force_fd(), READ, shutdown_listener)?;
+        let signal_listener = signal::Listener::new(
+            verbose,
+            self.signals.clone(),
+            self.shutdown,
+            self.hub.clone(),
+            index,
+            self.reloadable.clone(),
+            self.sockets.concat(),
+        )?;
+        let (signal_fd, inbox_fd) = (signal_listener.raw_fd(), signal_listener.inbox_fd());
+        let signal_listener = Rc::new(RefCell::new(signal_listener));
+        reactor.add_interest(signal_fd, READ, signal_listener.clone())?;
+        reactor.add_interest(inbox_fd, READ, signal_listener)?;
+
+        let timer_listener = timer::Listener::new()?;
+        reactor.add_interest(
+            timer_listener.raw_fd(),
+            READ,
+            Rc::new(RefCell::new(timer_listener)),
+        )?;
+
+        // the last one up lets the process this one replaces drain
+        if self.starting.fetch_sub(1, Ordering::AcqRel) == 1 {
+            if let Err(e) = upgrade::ready() {
+                log_error(&format!("could not report the upgrade: {e}"));
+            }
+        }
+        reactor.run(verbose)
+    }
+}
+
+const USAGE: &str = "\
+Usage: rust-epoll-example [OPTIONS]
+
+Options:
+  --listen ADDR:PORT         serve on this address, repeatable (127.0.0.1:8000);
+                             unix:PATH serves on a Unix socket
+  --backlog N                pending connections per socket (the system maximum)
+  --workers N                threads, each with its own reactor (1)
+  --max-connections N        connections each worker serves at once on each
+                             listener (1024)
+  --log-level LEVEL          error, info or debug (info)
+  -v, --verbose              same as --log-level debug
+  --log-file FILE            append the log to FILE, opened again on
+                             reopen-logs
+  --config FILE              read settings from FILE, again on SIGHUP
+  --check-config             check the settings and exit
+  --backend BACKEND          epoll or io_uring (epoll)
+  --on-error POLICY          isolate or exit on a failing receiver (isolate)
+  --signal NAME=ACTION       what a signal does, repeatable
+  --max-requests N           requests per keep-alive connection (1000)
+  --upload-dir DIR           where request bodies are stored
+  --max-header-size BYTES    largest request head (16384)
+  --max-header-count N       most header fields (100)
+  --max-body-size BYTES      largest request body (104857600)
+  --header-timeout SECS      to send a request head (10)
+  --body-timeout SECS        between two reads of a body (30)
+  --keep-alive-timeout SECS  for the next request on a connection (15)
+  --write-timeout SECS       between two writes of a response (30)
+  --drain-timeout SECS       for the connections left on shutdown (30)
+  -h, --help                 print this help
+  -V, --version              print the version
+";
+
+/// What the command line asks for.
+struct Options {
+    signals: Signals,
+    /// Settings that apply on top of the configuration file, if any.
+    overrides: Overrides,
+    config_path: Option<PathBuf>,
+    /// Only check the configuration.
+    check_config: bool,
+}
+
+fn parse_args(mut args: impl Iterator<Item = String>) -> Result<Options, String> {
+    let mut options = Options {
+        signals: Signals::default(),
+        overrides: Overrides::default(),
+        config_path: None,
+        check_config: false,
+    };
+    let overrides = &mut options.overrides;
+    while let Some(arg) = args.next() {
         match &arg[..] {
-            "-v" | "--verbose" => {
-                verbose = true;
+            "-h" | "--help" => {
+                print!("{USAGE}");
+                std::process::exit(0);
+            }
+            "-V" | "--version" => {
+                println!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+                std::process::exit(0);
             }
-            _ => {}
+            "-v" | "--verbose" => overrides.set("log-level", "debug".to_owned())?,
+            "--listen" => overrides.listen(parse_value(&arg, args.next())?),
+            "--config" => options.config_path = Some(parse_value(&arg, args.next())?),
+            "--check-config" => options.check_config = true,
+            "--signal" => options.signals.bind(parse_value(&arg, args.next())?),
+            // named like the keys of the configuration file
+            "--log-level"
+            | "--log-file"
+            | "--backlog"
+            | "--workers"
+            | "--backend"
+            | "--on-error"
+            | "--max-connections"
+            | "--max-requests"
+            | "--upload-dir"
+            | "--max-header-size"
+            | "--max-header-count"
+            | "--max-body-size"
+            | "--header-timeout"
+            | "--body-timeout"
+            | "--keep-alive-timeout"
+            | "--write-timeout"
+            | "--drain-timeout" => {
+                let value = args
+                    .next()
+                    .ok_or_else(|| format!("missing value for {arg}"))?;
+                overrides.set(&arg[2..], value)?;
+            }
+            _ => return Err(format!("unknown option {arg}")),
         }
     }
+    Ok(options)
+}
 
-    let mut reactor = Reactor::new()?;
-    let content_handle = content_actor::Handle::new()?;
-    let req_handle = request_context::Handle::new()?;
-    let req_actor = req_handle.bind(&mut reactor, verbose, content_handle.clone())?;
-    content_handle.bind(&mut reactor, verbose, req_handle)?;
+/// The listening sockets of each of `workers`, one for each listener:
+/// those handed over by the process this one replaces or by the service
+/// manager, or new ones.
+fn listen(config: &Config, workers: usize) -> std::io::Result<Vec<Vec<RawFd>>> {
+    if let Some(fds) = upgrade::inherited()? {
+        if fds.is_empty() {
+            return Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "inherited no sockets",
+            ));
+        }
+        return distribute(&fds, workers);
+    }
+    if let Some(sockets) = activation::listen_fds()? {
+        if config.verbose() {
+            for socket in &sockets {
+                log(&format!(
+                    "activated socket fd {}: {}",
+                    socket.fd, socket.name
+                ));
+            }
+        }
+        // every worker accepts on each of them
+        let first: Vec<RawFd> = sockets.iter().map(|socket| socket.fd).collect();
+        return shared(first, workers);
+    }
+    let mut sockets: Vec<Vec<RawFd>> = vec![Vec::new(); workers];
+    for spec in &config.listeners {
+        let backlog = spec.backlog.unwrap_or(config.backlog);
+        match &spec.address {
+            ListenAddr::Tcp(addr) => {
+                for worker in &mut sockets {
+                    worker.push(request::bind(*addr, workers > 1, backlog)?);
+                }
+            }
+            // a path is bound once, and the workers share the socket
+            ListenAddr::Unix(path) => {
+                let fd = request::bind_unix(path, backlog)?;
+                for (index, worker) in sockets.iter_mut().enumerate() {
+                    worker.push(if index == 0 { fd } else { dup(fd)? });
+                }
+            }
+        }
+    }
+    Ok(sockets)
+}
 
-    let listener = request::Listener::new(verbose, req_actor)?;
-    reactor.add_interest(listener.raw_fd(), READ, Rc::new(RefCell::new(listener)))?;
+/// Spreads the sockets handed over by a process that may have run another
+/// number of workers, so that each worker accepts on every address: a
+/// worker short of a socket for an address gets a copy of one, and the
+/// sockets left over go to the workers in turn, since the kernel still
+/// hands connections to them.
+fn distribute(fds: &[RawFd], workers: usize) -> std::io::Result<Vec<Vec<RawFd>>> {
+    let mut groups: Vec<(ListenAddr, Vec<RawFd>)> = Vec::new();
+    for &fd in fds {
+        let address = request::local_addr(fd)?;
+        match groups.iter_mut().find(|(a, _)| *a == address) {
+            Some((_, group)) => group.push(fd),
+            None => groups.push((address, vec![fd])),
+        }
+    }
+    let mut sockets: Vec<Vec<RawFd>> = vec![Vec::new(); workers];
+    for (_, group) in &groups {
+        for (index, worker) in sockets.iter_mut().enumerate() {
+            worker.push(match group.get(index) {
+                Some(&fd) => fd,
+                None => dup(group[index % group.len()])?,
+            });
+        }
+        for (index, &fd) in group.iter().enumerate().skip(workers) {
+            sockets[index % workers].push(fd);
+        }
+    }
+    Ok(sockets)
+}
 
-    let signal_listener = signal::Listener::new()?;
-    reactor.add_interest(
-        signal_listener.raw_fd(),
-        READ,
-        Rc::new(RefCell::new(signal_listener)),
-    )?;
+/// `first` for the first of `workers` and copies of it for the others.
+fn shared(first: Vec<RawFd>, workers: usize) -> std::io::Result<Vec<Vec<RawFd>>> {
+    let mut sockets = vec![first];
+    for _ in 1..workers {
+        let copies = sockets[0].iter().map(|&fd| dup(fd));
+        sockets.push(copies.collect::<Result<_, _>>()?);
+    }
+    Ok(sockets)
+}
 
-    let timer_listener = timer::Listener::new()?;
-    reactor.add_interest(
-        timer_listener.raw_fd(),
-        READ,
-        Rc::new(RefCell::new(timer_listener)),
-    )?;
+fn dup(fd: RawFd) -> std::io::Result<RawFd> {
+    syscall!(fcntl(fd, libc::F_DUPFD_CLOEXEC, 0))
+}
 
-    reactor.run(verbose)?;
+fn main() -> std::io::Result<()> {
+    let options = parse_args(std::env::args().skip(1)).unwrap_or_else(|e| {
+        eprintln!("{e}\nsee --help for the options");
+        std::process::exit(2);
+    });
+    let reloadable = Arc::new(Reloadable::new(
+        options.overrides,
+        options.config_path,
+        is_handler,
+    ));
+    if options.check_config {
+        match reloadable.load() {
+            Ok(_) => println!("configuration ok"),
+            Err(e) => {
+                eprintln!("{e}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+    let config = reloadable.load()?;
+    set_log_level(config.log_level);
+    if let Some(path) = &config.log_file {
+        set_log_file(path)?;
+    }
+    let workers = config.workers.max(1);
+    let sockets = listen(&config, workers)?;
+    let shutdown = Shutdown::new()?;
+    let hub = Arc::new(Hub::new(workers)?);
+    // before any worker starts so that they all inherit the blocked mask
+    options.signals.block()?;
+    let worker = Worker {
+        config,
+        sockets: &sockets,
+        shutdown: &shutdown,
+        signals: &options.signals,
+        hub: &hub,
+        reloadable: &reloadable,
+        starting: &AtomicUsize::new(workers),
+    };
+    std::thread::scope(|scope| {
+        let worker = &worker;
+        let others: Vec<_> = (1..workers)
+            .map(|index| scope.spawn(move || worker.run(index)))
+            .collect();
+        let mut result = worker.run(0);
+        for handle in others {
+            let joined = handle
+                .join()
+                .unwrap_or_else(|e| std::panic::resume_unwind(e));
+            result = result.and(joined);
+        }
+        result
+    })?;
     println!("exited");
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+    use std::io::{Read, Write};
+    use std::net::{SocketAddr, TcpListener, TcpStream};
+    use std::sync::atomic::{AtomicU8, Ordering};
+    use std::sync::Arc;
+
+    use super::*;
+    use crate::backend::BackendKind;
+
+    const WARM_UP: u8 = 0;
+    const MEASURING: u8 = 1;
+    const DONE: u8 = 2;
+
+    thread_local! {
+        static ALLOCATIONS: Cell<usize> = const { Cell::new(0) };
+    }
+
+    /// Counts the allocations made by each thread.
+    struct Counting;
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            unsafe { System.alloc(layout) }
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            unsafe { System.dealloc(ptr, layout) }
+        }
+
+        unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+            let _ = ALLOCATIONS.try_with(|n| n.set(n.get() + 1));
+            unsafe { System.realloc(ptr, layout, new_size) }
+        }
+    }
+
+    #[global_allocator]
+    static GLOBAL: Counting = Counting;
+
+    fn allocations() -> usize {
+        ALLOCATIONS.with(Cell::get)
+    }
+
+    /// Sends `requests` keep-alive requests on each of `connections`
+    /// connections made one after the other.
+    fn load(addr: SocketAddr, connections: usize, requests: usize) {
+        let mut buf = [0; 1024];
+        for _ in 0..connections {
+            let mut stream = TcpStream::connect(addr).unwrap();
+            for _ in 0..requests {
+                stream
+                    .write_all(b"GET / HTTP/1.1\r\nhost: test\r\n\r\n")
+                    .unwrap();
+                let mut len = 0;
+                while !buf[..len].ends_with(b"Hello") {
+                    let n = stream.read(&mut buf[len..]).unwrap();
+                    assert!(n > 0, "connection closed early");
+                    len += n;
+                }
+            }
+        }
+    }
+
+    fn test_routes(_config: &Config) -> Router {
+        let mut router = Router::new();
+        router
+            .get("/", |_: &Request, _: &Params<'_>, _: &Body<'_>| {
+                Response::new(200).with_static_body(b"Hello")
+            })
+            .unwrap();
+        router
+    }
+
+    /// A reactor serving [`test_routes`] on a free port, with the handle
+    /// of its content actor, which must be kept as long as it runs.
+    fn test_server(backend: BackendKind) -> (Reactor, SocketAddr, content_actor::Handle) {
+        let addr = TcpListener::bind("127.0.0.1:0")
+            .and_then(|probe| probe.local_addr())
+            .unwrap();
+        let mut reactor = Reactor::new(backend).unwrap();
+        let content_handle = content_actor::Handle::new().unwrap();
+        let req_handle = request_context::Handle::new().unwrap();
+        let req_actor = req_handle
+            .bind(
+                &mut reactor,
+                &Config::default(),
+                vec![ListenAddr::Tcp(addr)],
+                test_routes,
+                content_handle.clone(),
+            )
+            .unwrap();
+        content_handle
+            .bind(&mut reactor, false, req_handle)
+            .unwrap();
+        let socket = request::bind(addr, false, libc::SOMAXCONN).unwrap();
+        let listener = request::Listener::new(false, req_actor, socket, 0).unwrap();
+        reactor
+            .add_interest(listener.raw_fd(), READ, Rc::new(RefCell::new(listener)))
+            .unwrap();
+        (reactor, addr, content_handle)
+    }
+
+    fn assert_steady_state_does_not_allocate(backend: BackendKind) {
+        let (mut reactor, addr, _content) = test_server(backend);
+        let phase = Arc::new(AtomicU8::new(WARM_UP));
+        let client = std::thread::spawn({
+            let phase = phase.clone();
+            move || {
+                load(addr, 50, 10);
+                phase.store(MEASURING, Ordering::SeqCst);
+                load(addr, 50, 10);
+                phase.store(DONE, Ordering::SeqCst);
+                // wakes the reactor up to notice
+                drop(TcpStream::connect(addr));
+            }
+        });
+
+        let mut start = None;
+        loop {
+            let current = phase.load(Ordering::SeqCst);
+            if current >= MEASURING && start.is_none() {
+                start = Some(allocations());
+            }
+            if current == DONE {
+                break;
+            }
+            reactor.run_once(false).unwrap();
+        }
+        let allocated = allocations() - start.unwrap();
+        client.join().unwrap();
+        assert_eq!(allocated, 0, "allocations once warmed up");
+    }
+
+    #[test]
+    fn epoll_loop_does_not_allocate() {
+        assert_steady_state_does_not_allocate(BackendKind::Epoll);
+    }
+
+    #[test]
+    fn io_uring_loop_does_not_allocate() {
+        if BackendKind::IoUring.create().is_err() {
+            // not available here
+            return;
+        }
+        assert_steady_state_does_not_allocate(BackendKind::IoUring);
+    }
+
+    /// Serves the same load with each backend and prints the throughput:
+    /// kept-alive connections, then a connection per request. Run it with
+    /// `cargo test --release compare_backends -- --ignored --nocapture`.
+    #[test]
+    #[ignore = "a benchmark, slow in debug builds"]
+    fn compare_backends() {
+        const CLIENTS: usize = 8;
+        // per client, in ten connections (the most one may serve by
+        // default) or one each
+        const REQUESTS: usize = 10_000;
+
+        for backend in [BackendKind::Epoll, BackendKind::IoUring] {
+            if backend.create().is_err() {
+                println!("{backend:?}: not available");
+                continue;
+            }
+            for (connections, requests) in [(10, REQUESTS / 10), (REQUESTS, 1)] {
+                let (mut reactor, addr, _content) = test_server(backend);
+                let done = Arc::new(AtomicUsize::new(0));
+                let started = std::time::Instant::now();
+                let clients: Vec<_> = (0..CLIENTS)
+                    .map(|_| {
+                        let done = done.clone();
+                        std::thread::spawn(move || {
+                            load(addr, connections, requests);
+                            done.fetch_add(1, Ordering::SeqCst);
+                            // wakes the reactor up to notice
+                            drop(TcpStream::connect(addr));
+                        })
+                    })
+                    .collect();
+                while done.load(Ordering::SeqCst) < CLIENTS {
+                    reactor.run_once(false).unwrap();
+                }
+                let elapsed = started.elapsed().as_secs_f64();
+                for client in clients {
+                    client.join().unwrap();
+                }
+                let total = u32::try_from(CLIENTS * connections * requests).unwrap();
+                println!(
+                    "{backend:?}, {requests} per connection: {:.0} requests/s",
+                    f64::from(total) / elapsed
+                );
+            }
+        }
+    }
+
+    /// Mode of [`activated_child`] in a process started by [`activate`].
+    const ACTIVATION_MODE: &str = "ACTIVATION_TEST";
+
+    /// Starts this test binary again the way a service manager does, with
+    /// `socket` as fd 3, `LISTEN_FDS=count` and `LISTEN_PID` set to the id of
+    /// the new process, to run [`activated_child`] in `mode`. Returns what
+    /// it answers on `socket`.
+    fn activate(socket: &TcpListener, count: &str, mode: &str) -> String {
+        use std::ffi::CString;
+        use std::os::fd::AsRawFd;
+
+        let exe = std::env::current_exe().unwrap().into_os_string();
+        let exe = CString::new(exe.into_encoded_bytes()).unwrap();
+        let args: Vec<CString> = [
+            "tests::activated_child",
+            "--exact",
+            "--quiet",
+            "--test-threads=1",
+        ]
+        .into_iter()
+        .map(|arg| CString::new(arg).unwrap())
+        .collect();
+        let fds = CString::new(format!("LISTEN_FDS={count}")).unwrap();
+        let mode = CString::new(format!("{ACTIVATION_MODE}={mode}")).unwrap();
+        // the id is only known in the child, which writes it in place
+        let mut pid = *b"LISTEN_PID=0000000000\0";
+        let mut pointers: Vec<*const libc::c_char> = vec![exe.as_ptr()];
+        pointers.extend(args.iter().map(|arg| arg.as_ptr()));
+        pointers.push(std::ptr::null());
+        let envp = [
+            fds.as_ptr(),
+            mode.as_ptr(),
+            pid.as_ptr().cast::<libc::c_char>(),
+            std::ptr::null(),
+        ];
+        let fd = socket.as_raw_fd();
+        let null = CString::new("/dev/null").unwrap();
+        // nothing but async-signal-safe calls between fork and exec
+        let child = unsafe { libc::fork() };
+        assert!(child >= 0, "fork failed");
+        if child == 0 {
+            unsafe {
+                libc::dup2(fd, 3);
+                libc::fcntl(3, libc::F_SETFD, 0);
+                // its report would mix with this one; failures go to stderr
+                libc::dup2(libc::open(null.as_ptr(), libc::O_WRONLY), 1);
+                let mut id = libc::getpid();
+                for digit in pid[11..21].iter_mut().rev() {
+                    #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+                    let last = (id % 10) as u8;
+                    *digit = b'0' + last;
+                    id /= 10;
+                }
+                libc::execve(exe.as_ptr(), pointers.as_ptr(), envp.as_ptr());
+                libc::_exit(127);
+            }
+        }
+        let mut status = 0;
+        unsafe { libc::waitpid(child, &raw mut status, 0) };
+        assert!(libc::WIFEXITED(status), "child did not exit");
+        // its connection waits in the backlog, unless an assertion failed
+        let mut answer = String::new();
+        socket.set_nonblocking(true).unwrap();
+        if let Ok((mut stream, _)) = socket.accept() {
+            stream.set_nonblocking(false).unwrap();
+            stream.read_to_string(&mut answer).unwrap();
+        }
+        socket.set_nonblocking(false).unwrap();
+        answer
+    }
+
+    /// Checks the sockets it was started with by [`activate`], then
+    /// connects back through them and answers with its mode.
+    #[test]
+    fn activated_child() {
+        let Ok(mode) = std::env::var(ACTIVATION_MODE) else {
+            return;
+        };
+        let sockets = match mode.as_str() {
+            "serve" => {
+                // every worker gets the socket, the others a copy
+                let sockets = listen(&Config::default(), 2).unwrap();
+                assert_eq!(sockets[0], [3]);
+                assert_eq!(sockets[1].len(), 1);
+                sockets
+            }
+            "none" => {
+                assert!(activation::listen_fds().unwrap().is_none());
+                vec![vec![3]]
+            }
+            _ => {
+                assert!(activation::listen_fds().is_err());
+                vec![vec![3]]
+            }
+        };
+        let fd = *sockets.last().unwrap().first().unwrap();
+        let addr = request::local_addr(fd).unwrap();
+        let ListenAddr::Tcp(addr) = addr else {
+            panic!("not a TCP socket: {addr:?}");
+        };
+        TcpStream::connect(addr)
+            .unwrap()
+            .write_all(mode.as_bytes())
+            .unwrap();
+    }
+
+    #[test]
+    fn adopts_activated_sockets() {
+        let socket = TcpListener::bind("127.0.0.1:0").unwrap();
+        assert_eq!(activate(&socket, "1", "serve"), "serve");
+        assert_eq!(activate(&socket, "0", "none"), "none");
+        assert_eq!(activate(&socket, "-1", "invalid"), "invalid");
+    }
+}
@@ -0,0 +1,488 @@
+use std::io::{Error, ErrorKind};
+use std::path::{Path, PathBuf};
+
+use crate::http::Request;
+use crate::upload::{self, Upload};
+
+/// Part headers beyond this size are rejected.
+const MAX_HEADERS_SIZE: usize = 8 * 1024;
+/// Plain fields are kept in memory, so they are capped, each and together.
+const MAX_FIELD_SIZE: usize = 64 * 1024;
+const MAX_FIELDS_SIZE: usize = 1024 * 1024;
+/// Parts of a body, each of which keeps its headers in memory.
+const MAX_PARTS: usize = 256;
+/// Transport padding allowed between a boundary and its line break.
+const MAX_PADDING: usize = 64;
+
+/// Uploaded file of a `multipart/form-data` body. The client-supplied
+/// `filename` is informational only; the data lives at `path`, which is
+/// named by the server and removed once the handler returns.
+pub struct FilePart {
+    pub name: String,
+    pub filename: String,
+    pub headers: Vec<(String, String)>,
+    pub path: PathBuf,
+}
+
+impl FilePart {
+    /// Returns the first part header named `name`, compared case-insensitively.
+    #[must_use]
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case(name))
+            .map(|(_, v)| v.as_str())
+    }
+
+    /// Moves the file to `to`, where it is kept.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the file cannot be moved.
+    pub fn persist(&self, to: &Path) -> std::io::Result<()> {
+        upload::persist(&self.path, to)
+    }
+}
+
+/// Decoded `multipart/form-data` body.
+#[derive(Default)]
+pub struct Form {
+    fields: Vec<(String, String)>,
+    files: Vec<FilePart>,
+}
+
+impl Form {
+    #[must_use]
+    pub fn field(&self, name: &str) -> Option<&str> {
+        self.fields
+            .iter()
+            .find(|(n, _)| n == name)
+            .map(|(_, v)| v.as_str())
+    }
+
+    pub fn fields(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.fields.iter().map(|(n, v)| (n.as_str(), v.as_str()))
+    }
+
+    #[must_use]
+    pub fn files(&self) -> &[FilePart] {
+        &self.files
+    }
+
+    pub(crate) fn remove_files(&self) {
+        for file in &self.files {
+            let _ = std::fs::remove_file(&file.path);
+        }
+    }
+}
+
+/// Returns the boundary of a `multipart/form-data` request.
+#[must_use]
+pub fn boundary(req: &Request) -> Option<&str> {
+    let content_type = req.header("content-type")?;
+    let mut params = content_type.split(';');
+    if !params
+        .next()?
+        .trim()
+        .eq_ignore_ascii_case("multipart/form-data")
+    {
+        return None;
+    }
+    params
+        .filter_map(|p| p.split_once('='))
+        .find(|(n, _)| n.trim().eq_ignore_ascii_case("boundary"))
+        .map(|(_, v)| v.trim().trim_matches('"'))
+        .filter(|b| !b.is_empty() && b.len() <= 70)
+}
+
+enum State {
+    /// Skipping anything before the first boundary.
+    Preamble,
+    /// Right after a boundary, which is followed by either CRLF or `--`.
+    Delimiter,
+    Headers,
+    Body,
+    /// After the closing boundary; the rest is ignored.
+    Epilogue,
+}
+
+enum PartSink {
+    Field { name: String, value: Vec<u8> },
+    File { part: FilePart, upload: Upload },
+}
+
+/// Streaming `multipart/form-data` parser. Body data is fed as it arrives;
+/// file parts are written to the upload directory and only a boundary's
+/// worth of bytes is held back between calls.
+pub struct Parser {
+    /// `CRLF--boundary`.
+    delimiter: Vec<u8>,
+    dir: PathBuf,
+    buf: Vec<u8>,
+    state: State,
+    part: Option<PartSink>,
+    form: Form,
+    /// Bytes of the plain fields so far.
+    fields_size: usize,
+}
+
+impl Parser {
+    #[must_use]
+    pub fn new(boundary: &str, dir: &Path) -> Self {
+        let mut delimiter = b"\r\n--".to_vec();
+        delimiter.extend_from_slice(boundary.as_bytes());
+        Self {
+            delimiter,
+            dir: dir.to_owned(),
+            // the first boundary may come without a preceding CRLF
+            buf: b"\r\n".to_vec(),
+            state: State::Preamble,
+            part: None,
+            form: Form::default(),
+            fields_size: 0,
+        }
+    }
+
+    /// Consumes the next piece of the body.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ErrorKind::InvalidData`] for a malformed body and the
+    /// underlying error if a file part cannot be written.
+    pub fn feed(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.buf.extend_from_slice(data);
+        loop {
+            let progress = match self.state {
+                State::Preamble => self.skip_preamble(),
+                State::Delimiter => self.after_delimiter()?,
+                State::Headers => self.parse_headers()?,
+                State::Body => self.copy_body()?,
+                State::Epilogue => {
+                    self.buf.clear();
+                    false
+                }
+            };
+            if !progress {
+                return Ok(());
+            }
+        }
+    }
+
+    /// Returns the form once the whole body has been fed.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`ErrorKind::InvalidData`] if the closing boundary is missing.
+    pub fn finish(mut self) -> std::io::Result<Form> {
+        if matches!(self.state, State::Epilogue) {
+            Ok(std::mem::take(&mut self.form))
+        } else {
+            Err(malformed("multipart body is truncated"))
+        }
+    }
+
+    fn find_delimiter(&self) -> Option<usize> {
+        self.buf
+            .windows(self.delimiter.len())
+            .position(|w| w == self.delimiter)
+    }
+
+    /// Number of bytes at the end of `buf` that might start a delimiter.
+    fn held_back(&self) -> usize {
+        self.buf.len().min(self.delimiter.len() - 1)
+    }
+
+    fn skip_preamble(&mut self) -> bool {
+        if let Some(i) = self.find_delimiter() {
+            self.buf.drain(..i + self.delimiter.len());
+            self.state = State::Delimiter;
+            true
+        } else {
+            self.buf.drain(..self.buf.len() - self.held_back());
+            false
+        }
+    }
+
+    fn after_delimiter(&mut self) -> std::io::Result<bool> {
+        // transport padding may precede the line break
+        let padding = self
+            .buf
+            .iter()
+            .position(|b| *b != b' ' && *b != b'\t')
+            .unwrap_or(self.buf.len());
+        if padding > MAX_PADDING {
+            return Err(malformed("multipart transport padding is too long"));
+        }
+        match &self.buf[padding..] {
+            [b'-', b'-', ..] if padding == 0 => {
+                self.state = State::Epilogue;
+                Ok(true)
+            }
+            [b'\r', b'\n', ..] => {
+                self.buf.drain(..padding + 2);
+                self.state = State::Headers;
+                Ok(true)
+            }
+            [] | [b'-' | b'\r'] => Ok(false),
+            _ => Err(malformed("malformed multipart boundary")),
+        }
+    }
+
+    fn parse_headers(&mut self) -> std::io::Result<bool> {
+        let end = if self.buf.starts_with(b"\r\n") {
+            0
+        } else if let Some(i) = self.buf.windows(4).position(|w| w == b"\r\n\r\n") {
+            i + 2
+        } else if self.buf.len() > MAX_HEADERS_SIZE {
+            return Err(malformed("multipart headers are too large"));
+        } else {
+            return Ok(false);
+        };
+        let block = std::str::from_utf8(&self.buf[..end])
+            .map_err(|_| malformed("multipart headers are not valid UTF-8"))?;
+        let mut headers = Vec::new();
+        for line in block.split("\r\n").filter(|l| !l.is_empty()) {
+            let (name, value) = line
+                .split_once(':')
+                .ok_or_else(|| malformed("malformed multipart header"))?;
+            headers.push((name.trim().to_owned(), value.trim().to_owned()));
+        }
+        self.buf.drain(..end + 2);
+        self.start_part(headers)?;
+        self.state = State::Body;
+        Ok(true)
+    }
+
+    fn start_part(&mut self, headers: Vec<(String, String)>) -> std::io::Result<()> {
+        let disposition = headers
+            .iter()
+            .find(|(n, _)| n.eq_ignore_ascii_case("content-disposition"))
+            .map(|(_, v)| v.as_str())
+            .ok_or_else(|| malformed("multipart part without content-disposition"))?;
+        let (kind, params) = disposition.split_once(';').unwrap_or((disposition, ""));
+        if !kind.trim().eq_ignore_ascii_case("form-data") {
+            return Err(malformed("multipart part is not form-data"));
+        }
+        let name = disposition_param(params, "name")
+            .ok_or_else(|| malformed("multipart part without a name"))?;
+        if self.form.fields.len() + self.form.files.len() >= MAX_PARTS {
+            return Err(malformed("too many multipart parts"));
+        }
+        self.part = Some(match disposition_param(params, "filename") {
+            Some(filename) => PartSink::File {
+                upload: Upload::create(&self.dir)?,
+                part: FilePart {
+                    name,
+                    filename,
+                    headers,
+                    path: PathBuf::new(),
+                },
+            },
+            None => PartSink::Field {
+                name,
+                value: Vec::new(),
+            },
+        });
+        Ok(())
+    }
+
+    fn copy_body(&mut self) -> std::io::Result<bool> {
+        let (len, found) = match self.find_delimiter() {
+            Some(i) => (i, true),
+            None => (self.buf.len() - self.held_back(), false),
+        };
+        match &mut self.part {
+            Some(PartSink::Field { value, .. }) => {
+                if value.len() + len > MAX_FIELD_SIZE {
+                    return Err(malformed("multipart field is too large"));
+                }
+                if self.fields_size + len > MAX_FIELDS_SIZE {
+                    return Err(malformed("multipart fields are too large"));
+                }
+                self.fields_size += len;
+                value.extend_from_slice(&self.buf[..len]);
+            }
+            Some(PartSink::File { upload, .. }) => upload.write(&self.buf[..len])?,
+            None => {}
+        }
+        if !found {
+            self.buf.drain(..len);
+            return Ok(false);
+        }
+        self.buf.drain(..len + self.delimiter.len());
+        match self.part.take() {
+            Some(PartSink::Field { name, value }) => {
+                let value = String::from_utf8_lossy(&value).into_owned();
+                self.form.fields.push((name, value));
+            }
+            Some(PartSink::File { mut part, upload }) => {
+                part.path = upload.finish()?;
+                self.form.files.push(part);
+            }
+            None => {}
+        }
+        self.state = State::Delimiter;
+        Ok(true)
+    }
+}
+
+impl Drop for Parser {
+    fn drop(&mut self) {
+        // an abandoned body leaves no files behind; `finish` moves the form
+        // out first when it succeeds
+        self.form.remove_files();
+    }
+}
+
+/// Looks up a `content-disposition` parameter, unquoting its value.
+fn disposition_param(params: &str, name: &str) -> Option<String> {
+    let mut rest = params;
+    while let Some((key, tail)) = rest.split_once('=') {
+        let key = key.trim_start_matches([';', ' ', '\t']).trim();
+        let tail = tail.trim_start();
+        let (value, next) = if let Some(quoted) = tail.strip_prefix('"') {
+            let mut value = String::new();
+            let mut chars = quoted.char_indices();
+            let mut end = quoted.len();
+            while let Some((i, c)) = chars.next() {
+                match c {
+                    '\\' => {
+                        if let Some((_, escaped)) = chars.next() {
+                            value.push(escaped);
+                        }
+                    }
+                    '"' => {
+                        end = i + 1;
+                        break;
+                    }
+                    c => value.push(c),
+                }
+            }
+            (value, &quoted[end..])
+        } else {
+            let (value, next) = tail.split_once(';').unwrap_or((tail, ""));
+            (value.trim().to_owned(), next)
+        };
+        if key.eq_ignore_ascii_case(name) {
+            return Some(value);
+        }
+        rest = next;
+    }
+    None
+}
+
+fn malformed(msg: &str) -> Error {
+    Error::new(ErrorKind::InvalidData, msg)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BODY: &[u8] = b"preamble\r\n\
+        --XyZ\r\n\
+        Content-Disposition: form-data; name=\"title\"\r\n\
+        \r\n\
+        a --XyZ look-alike\r\n\
+        --XyZ \t\r\n\
+        content-disposition: form-data; name=\"file\"; filename=\"a \\\"b\\\".txt\"\r\n\
+        Content-Type: text/plain\r\n\
+        \r\n\
+        line one\r\n\r\n-line two\r\n\
+        --XyZ--\r\n\
+        epilogue";
+
+    /// Feeds `body` in pieces of `step` bytes and finishes the form.
+    fn parse(body: &[u8], step: usize) -> std::io::Result<Form> {
+        let mut parser = Parser::new("XyZ", &std::env::temp_dir());
+        for piece in body.chunks(step) {
+            parser.feed(piece)?;
+        }
+        parser.finish()
+    }
+
+    fn check(form: &Form) {
+        assert_eq!(form.field("title"), Some("a --XyZ look-alike"));
+        let [file] = form.files() else {
+            panic!("expected one file");
+        };
+        assert_eq!(file.name, "file");
+        assert_eq!(file.filename, "a \"b\".txt");
+        assert_eq!(file.header("content-type"), Some("text/plain"));
+        assert_eq!(
+            std::fs::read(&file.path).unwrap(),
+            b"line one\r\n\r\n-line two"
+        );
+        form.remove_files();
+    }
+
+    #[test]
+    fn bodies_split_anywhere() {
+        for step in 1..=BODY.len() {
+            check(&parse(BODY, step).unwrap());
+        }
+    }
+
+    #[test]
+    fn boundary_split_across_reads() {
+        let at = BODY.windows(9).position(|w| w == b"\r\n--XyZ--").unwrap();
+        for split in at..at + 9 {
+            let mut parser = Parser::new("XyZ", &std::env::temp_dir());
+            parser.feed(&BODY[..split]).unwrap();
+            parser.feed(&BODY[split..]).unwrap();
+            check(&parser.finish().unwrap());
+        }
+    }
+
+    fn invalid(body: &[u8]) -> String {
+        let err = parse(body, body.len().max(1)).err().unwrap();
+        assert_eq!(err.kind(), ErrorKind::InvalidData);
+        err.to_string()
+    }
+
+    #[test]
+    fn missing_closing_boundary() {
+        let truncated = &BODY[..BODY.len() - 14];
+        for step in 1..=truncated.len() {
+            let err = parse(truncated, step).err().unwrap();
+            assert_eq!(err.kind(), ErrorKind::InvalidData);
+        }
+        assert_eq!(invalid(b""), "multipart body is truncated");
+    }
+
+    #[test]
+    fn malformed_parts() {
+        assert_eq!(
+            invalid(b"--XyZ\r\nContent-Type: text/plain\r\n\r\nx\r\n--XyZ--"),
+            "multipart part without content-disposition"
+        );
+        assert_eq!(
+            invalid(b"--XyZ\r\nContent-Disposition: attachment\r\n\r\nx\r\n--XyZ--"),
+            "multipart part is not form-data"
+        );
+        assert_eq!(invalid(b"--XyZx\r\n"), "malformed multipart boundary");
+    }
+
+    #[test]
+    fn limits() {
+        let mut padded = b"--XyZ".to_vec();
+        padded.resize(padded.len() + MAX_PADDING + 1, b' ');
+        assert_eq!(invalid(&padded), "multipart transport padding is too long");
+
+        let field = b"--XyZ\r\nContent-Disposition: form-data; name=\"f\"\r\n\r\n";
+        let mut many = Vec::new();
+        for _ in 0..=MAX_PARTS {
+            many.extend_from_slice(field);
+            many.extend_from_slice(b"x\r\n");
+        }
+        assert_eq!(invalid(&many), "too many multipart parts");
+
+        let mut large = Vec::new();
+        for _ in 0..=MAX_FIELDS_SIZE / MAX_FIELD_SIZE {
+            large.extend_from_slice(field);
+            large.resize(large.len() + MAX_FIELD_SIZE, b'x');
+            large.extend_from_slice(b"\r\n");
+        }
+        assert_eq!(invalid(&large), "multipart fields are too large");
+    }
+}
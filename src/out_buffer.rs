@@ -0,0 +1,106 @@
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::raw::c_void;
+
+use crate::http::Payload;
+
+/// Response bytes waiting to be written to a socket: the serialized head
+/// and the body, sent together with `writev` and resumed after short writes.
+/// File bodies follow the head with `sendfile`.
+#[derive(Default)]
+pub struct OutBuffer {
+    head: Vec<u8>,
+    body: Payload,
+    written: usize,
+}
+
+impl OutBuffer {
+    /// Drops whatever is buffered and returns the head buffer to be filled
+    /// with the next response.
+    pub fn start(&mut self) -> &mut Vec<u8> {
+        self.head.clear();
+        self.body = Payload::default();
+        self.written = 0;
+        &mut self.head
+    }
+
+    pub fn set_body(&mut self, body: Payload) {
+        self.body = body;
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.written == self.head.len() + self.body.len()
+    }
+
+    /// Writes as much as the socket accepts. Returns `Ok(true)` once
+    /// everything is written and `Ok(false)` if the socket would block.
+    ///
+    /// # Errors
+    ///
+    /// Returns the error of a failed `writev` or `sendfile` other than
+    /// `EAGAIN`, and `UnexpectedEof` if a file body got shorter than its
+    /// announced length.
+    pub fn flush(&mut self, fd: RawFd) -> std::io::Result<bool> {
+        while !self.is_empty() {
+            let bytes = match &self.body {
+                Payload::Bytes(bytes) => &bytes[..],
+                Payload::File(file, len) if self.written >= self.head.len() => {
+                    let sent = self.written - self.head.len();
+                    #[allow(clippy::cast_possible_wrap)]
+                    let mut offset = sent as libc::off_t;
+                    let res = unsafe {
+                        libc::sendfile(fd, file.as_raw_fd(), &raw mut offset, len - sent)
+                    };
+                    match res {
+                        0 => return Err(std::io::ErrorKind::UnexpectedEof.into()),
+                        res if res < 0 => {
+                            let e = std::io::Error::last_os_error();
+                            match e.kind() {
+                                std::io::ErrorKind::WouldBlock => return Ok(false),
+                                std::io::ErrorKind::Interrupted => continue,
+                                _ => return Err(e),
+                            }
+                        }
+                        #[allow(clippy::cast_sign_loss)]
+                        res => self.written += res as usize,
+                    }
+                    continue;
+                }
+                Payload::File(..) => &[][..],
+            };
+            let (head, body) = if self.written < self.head.len() {
+                (&self.head[self.written..], bytes)
+            } else {
+                (&[][..], &bytes[self.written - self.head.len()..])
+            };
+            let iov = [
+                libc::iovec {
+                    iov_base: head.as_ptr().cast_mut().cast::<c_void>(),
+                    iov_len: head.len(),
+                },
+                libc::iovec {
+                    iov_base: body.as_ptr().cast_mut().cast::<c_void>(),
+                    iov_len: body.len(),
+                },
+            ];
+            let (iov, cnt) = if head.is_empty() {
+                (&iov[1..], 1)
+            } else {
+                (&iov[..], 2)
+            };
+            let res = unsafe { libc::writev(fd, iov.as_ptr(), cnt) };
+            if res < 0 {
+                let e = std::io::Error::last_os_error();
+                return match e.kind() {
+                    std::io::ErrorKind::WouldBlock => Ok(false),
+                    std::io::ErrorKind::Interrupted => continue,
+                    _ => Err(e),
+                };
+            }
+            #[allow(clippy::cast_sign_loss)]
+            let sz = res as usize;
+            self.written += sz;
+        }
+        Ok(true)
+    }
+}
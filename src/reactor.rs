@@ -1,13 +1,23 @@
 use std::cell::RefCell;
-use std::collections::{HashMap, VecDeque};
+use std::collections::VecDeque;
 use std::os::fd::RawFd;
 use std::rc::Rc;
+use std::str::FromStr;
+use std::time::Instant;
 
-use crate::{log, syscall};
+use crate::backend::{Backend, BackendKind};
+use crate::signal::SignalEvent;
+use crate::timer_queue::{TimerOp, TimerQueue};
+use crate::{log, log_error};
 
 pub struct State(i32);
 
 impl State {
+    #[allow(clippy::cast_possible_wrap)]
+    pub(crate) fn new(events: u32) -> Self {
+        Self(events as i32)
+    }
+
     #[inline]
     #[must_use]
     pub fn read(&self) -> bool {
@@ -25,32 +35,182 @@ impl State {
         self.0 & (libc::EPOLLOUT | libc::EPOLLIN) != 0
     }
 
+    #[inline]
+    fn error(&self) -> bool {
+        self.0 & libc::EPOLLERR != 0
+    }
+
     #[inline]
     fn shutdown(&self) -> bool {
         self.0 & (libc::EPOLLRDHUP | libc::EPOLLPRI | libc::EPOLLERR | libc::EPOLLHUP) != 0
     }
 }
 
+/// Identifies one registration with a [`Reactor`]. The index of a removed
+/// registration is reused with the next generation, so a token that
+/// outlived its fd never reaches the fd's next owner.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Token {
+    index: u32,
+    generation: u32,
+}
+
+impl Token {
+    pub(crate) fn index(self) -> usize {
+        self.index as usize
+    }
+
+    pub(crate) fn generation(self) -> u32 {
+        self.generation
+    }
+
+    pub(crate) fn to_u64(self) -> u64 {
+        u64::from(self.generation) << 32 | u64::from(self.index)
+    }
+
+    #[allow(clippy::cast_possible_truncation)]
+    pub(crate) fn from_u64(value: u64) -> Self {
+        Self {
+            index: value as u32,
+            generation: (value >> 32) as u32,
+        }
+    }
+}
+
+/// Why a registration went away, as told to [`EventReceiver::on_unregister`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnregisterReason {
+    /// [`InterestAction::Remove`] was applied.
+    Removed,
+    /// The peer hung up or the fd reported an error.
+    HungUp,
+    /// The receiver failed with an error that was not fatal.
+    Failed,
+    /// The reactor is being dropped.
+    Shutdown,
+}
+
+/// Decides which errors of a receiver stop the reactor. The others are
+/// logged and cost the receiver the registration that failed.
+#[derive(Clone, Copy, Debug, Default)]
+pub enum FaultPolicy {
+    /// Only errors that the process cannot go on after are fatal.
+    #[default]
+    Isolate,
+    /// Every error is fatal.
+    Exit,
+}
+
+impl FaultPolicy {
+    fn is_fatal(self, error: &std::io::Error) -> bool {
+        match self {
+            FaultPolicy::Isolate => error.kind() == std::io::ErrorKind::OutOfMemory,
+            FaultPolicy::Exit => true,
+        }
+    }
+}
+
+impl FromStr for FaultPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "isolate" => Ok(FaultPolicy::Isolate),
+            "exit" => Ok(FaultPolicy::Exit),
+            _ => Err(format!("unknown error policy {s}")),
+        }
+    }
+}
+
 pub(crate) trait EventReceiver {
     fn on_ready(
         &mut self,
         ready_to: State,
         fd: RawFd,
+        token: Token,
         new_actions: &mut InterestActions,
     ) -> std::io::Result<()>;
+
+    /// Called once `fd` is registered for this receiver under `token`.
+    fn on_register(
+        &mut self,
+        _fd: RawFd,
+        _token: Token,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called once `fd` is deregistered, before the reactor closes it if
+    /// it owns it. Whatever the receiver keeps for `token` should be
+    /// released here. Actions queued on [`UnregisterReason::Shutdown`] are
+    /// dropped.
+    fn on_unregister(
+        &mut self,
+        _fd: RawFd,
+        _token: Token,
+        _reason: UnregisterReason,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called with the pending error of `fd` when it reports an error
+    /// condition, or with the error this receiver failed with for it, right
+    /// before it is unregistered.
+    fn on_error(
+        &mut self,
+        _fd: RawFd,
+        _token: Token,
+        _error: &std::io::Error,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called with a signal event, once for each receiver however many fds
+    /// it is registered for.
+    fn on_signal(
+        &mut self,
+        _event: &SignalEvent,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
+
+    /// Called when the timer `key` scheduled for `token` through
+    /// [`InterestAction::Timer`] is due.
+    fn on_timer(
+        &mut self,
+        _token: Token,
+        _key: u64,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        Ok(())
+    }
 }
 
 pub const READ: u32 = (libc::EPOLLONESHOT | libc::EPOLLIN) as _;
 pub const WRITE: u32 = (libc::EPOLLONESHOT | libc::EPOLLOUT) as _;
 
 pub(crate) enum InterestAction {
+    /// Registers the fd and hands it over to the reactor, which closes it
+    /// once unregistered; the receiver learns its token in
+    /// [`EventReceiver::on_register`].
     Add(RawFd, u32, Rc<RefCell<dyn EventReceiver>>),
-    Modify(RawFd, u32),
-    Remove(RawFd),
+    Modify(Token, u32),
+    /// Deregisters the fd of the token, closing it if the reactor owns it.
+    Remove(Token),
+    /// Schedules, reschedules or cancels the timer `key` of the
+    /// registration. Its timers go away along with it.
+    Timer(Token, u64, TimerOp),
+    /// Hands the event to every registered receiver.
+    Signal(SignalEvent),
     Exit,
     PrintStats,
 }
 
+#[derive(Default)]
 pub(crate) struct InterestActions {
     actions: VecDeque<InterestAction>,
 }
@@ -75,146 +235,384 @@ impl Iterator for InterestActions {
     }
 }
 
+struct Registration {
+    fd: RawFd,
+    /// Whether the fd came with [`InterestAction::Add`] and is closed along
+    /// with the registration.
+    owned: bool,
+    receiver: Rc<RefCell<dyn EventReceiver>>,
+}
+
+struct Slot {
+    generation: u32,
+    registration: Option<Registration>,
+}
+
 pub struct Reactor {
-    epoll_fd: RawFd,
-    receivers: HashMap<RawFd, Rc<RefCell<dyn EventReceiver>>>,
+    backend: Box<dyn Backend>,
+    slots: Vec<Slot>,
+    /// Indices of the empty slots.
+    free: Vec<u32>,
+    timers: TimerQueue,
+    fault_policy: FaultPolicy,
+    // kept between turns of the loop so that their capacity is reused
+    events: Vec<(Token, State)>,
+    actions: InterestActions,
 }
 
 impl Reactor {
-    pub(crate) fn new() -> std::io::Result<Self> {
-        let epoll_fd = syscall!(epoll_create1(0))?;
-        if let Ok(flags) = syscall!(fcntl(epoll_fd, libc::F_GETFD)) {
-            let _ = syscall!(fcntl(epoll_fd, libc::F_SETFD, flags | libc::FD_CLOEXEC));
-        }
+    pub(crate) fn new(backend: BackendKind) -> std::io::Result<Self> {
         Ok(Self {
-            epoll_fd,
-            receivers: HashMap::new(),
+            backend: backend.create()?,
+            slots: Vec::new(),
+            free: Vec::new(),
+            timers: TimerQueue::default(),
+            fault_policy: FaultPolicy::default(),
+            events: Vec::new(),
+            actions: InterestActions::new(),
         })
     }
 
+    #[must_use]
+    pub(crate) fn with_fault_policy(mut self, fault_policy: FaultPolicy) -> Self {
+        self.fault_policy = fault_policy;
+        self
+    }
+
+    /// Registers `fd` for `receiver` and returns its token. The fd stays
+    /// owned by the caller.
     pub(crate) fn add_interest(
         &mut self,
         fd: RawFd,
         events: u32,
         receiver: Rc<RefCell<dyn EventReceiver>>,
-    ) -> std::io::Result<()> {
-        #[allow(clippy::cast_sign_loss)]
-        let mut event = libc::epoll_event {
-            events,
-            u64: fd as u64,
+    ) -> std::io::Result<Token> {
+        let mut actions = InterestActions::new();
+        let token = self.register(fd, events, false, receiver, &mut actions)?;
+        self.apply(&mut actions)?;
+        Ok(token)
+    }
+
+    fn register(
+        &mut self,
+        fd: RawFd,
+        events: u32,
+        owned: bool,
+        receiver: Rc<RefCell<dyn EventReceiver>>,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<Token> {
+        let index = match self.free.last() {
+            Some(index) => *index,
+            None => u32::try_from(self.slots.len()).map_err(|_| {
+                std::io::Error::new(std::io::ErrorKind::OutOfMemory, "too many registrations")
+            })?,
         };
-        syscall!(epoll_ctl(
-            self.epoll_fd,
-            libc::EPOLL_CTL_ADD,
+        let generation = self
+            .slots
+            .get(index as usize)
+            .map_or(0, |slot| slot.generation);
+        let token = Token { index, generation };
+        self.backend.add(fd, events, token)?;
+        let registration = Some(Registration {
             fd,
-            &mut event
-        ))?;
-        self.receivers.insert(fd, receiver);
-        Ok(())
+            owned,
+            receiver,
+        });
+        if self.free.pop().is_some() {
+            self.slots[index as usize].registration = registration;
+        } else {
+            self.slots.push(Slot {
+                generation,
+                registration,
+            });
+        }
+        if let Some(registration) = self.registration(token) {
+            let registered = registration
+                .receiver
+                .borrow_mut()
+                .on_register(fd, token, new_actions);
+            if let Err(e) = registered {
+                self.isolate(token, e, new_actions)?;
+            }
+        }
+        Ok(token)
+    }
+
+    /// Returns the live registration of `token`, or `None` for a stale one.
+    fn registration(&self, token: Token) -> Option<&Registration> {
+        self.slots
+            .get(token.index as usize)
+            .filter(|slot| slot.generation == token.generation)
+            .and_then(|slot| slot.registration.as_ref())
     }
 
-    fn modify_interest(&self, fd: RawFd, events: u32) -> std::io::Result<()> {
-        #[allow(clippy::cast_sign_loss)]
-        let mut event = libc::epoll_event {
-            events,
-            u64: fd as u64,
+    fn modify_interest(&mut self, token: Token, events: u32) -> std::io::Result<()> {
+        let Some(fd) = self.registration(token).map(|r| r.fd) else {
+            return Ok(());
         };
-        syscall!(epoll_ctl(
-            self.epoll_fd,
-            libc::EPOLL_CTL_MOD,
+        self.backend.modify(fd, events, token)
+    }
+
+    fn remove_interest(
+        &mut self,
+        token: Token,
+        reason: UnregisterReason,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if self.registration(token).is_none() {
+            return Ok(());
+        }
+        self.timers.remove(token);
+        let slot = &mut self.slots[token.index as usize];
+        let Some(Registration {
             fd,
-            &mut event
-        ))?;
+            owned,
+            receiver,
+        }) = slot.registration.take()
+        else {
+            return Ok(());
+        };
+        slot.generation = slot.generation.wrapping_add(1);
+        self.free.push(token.index);
+        let removed = self.backend.remove(fd, token);
+        let unregistered = receiver
+            .borrow_mut()
+            .on_unregister(fd, token, reason, new_actions);
+        if owned {
+            let _ = unsafe { libc::close(fd) };
+        }
+        removed.and(unregistered)
+    }
+
+    /// Deals with an error of the receiver registered under `token`: a
+    /// fatal one is returned, any other is logged and the receiver loses
+    /// the registration.
+    fn isolate(
+        &mut self,
+        token: Token,
+        error: std::io::Error,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if self.fault_policy.is_fatal(&error) {
+            return Err(error);
+        }
+        let Some((fd, receiver)) = self.registration(token).map(|r| (r.fd, r.receiver.clone()))
+        else {
+            log_error(&format!("error for a removed registration: {error}"));
+            return Ok(());
+        };
+        log_error(&format!("unregistering fd {fd} after an error: {error}"));
+        if let Err(e) = receiver
+            .borrow_mut()
+            .on_error(fd, token, &error, new_actions)
+        {
+            log_error(&format!("error while handling an error of fd {fd}: {e}"));
+        }
+        if let Err(e) = self.remove_interest(token, UnregisterReason::Failed, new_actions) {
+            log_error(&format!("could not unregister fd {fd}: {e}"));
+        }
         Ok(())
     }
 
-    fn remove_interest(&mut self, fd: RawFd) -> std::io::Result<()> {
-        syscall!(epoll_ctl(
-            self.epoll_fd,
-            libc::EPOLL_CTL_DEL,
-            fd,
-            std::ptr::null_mut()
-        ))?;
-        self.receivers.remove(&fd);
-        let _ = unsafe { libc::close(fd) };
+    fn signal(
+        &mut self,
+        event: &SignalEvent,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if matches!(event, SignalEvent::DumpStats) {
+            self.print_stats();
+        }
+        let mut receivers: Vec<_> = self
+            .slots
+            .iter()
+            .filter_map(|slot| slot.registration.as_ref())
+            .map(|r| r.receiver.clone())
+            .collect();
+        receivers.sort_by_key(|receiver| Rc::as_ptr(receiver).cast::<u8>());
+        receivers.dedup_by(|a, b| Rc::ptr_eq(a, b));
+        for receiver in receivers {
+            if let Err(e) = receiver.borrow_mut().on_signal(event, new_actions) {
+                if self.fault_policy.is_fatal(&e) {
+                    return Err(e);
+                }
+                log_error(&format!("could not handle {event:?}: {e}"));
+            }
+        }
         Ok(())
     }
 
-    fn apply(&mut self, actions: InterestActions) -> std::io::Result<bool> {
+    fn print_stats(&self) {
+        let registered = self.slots.len() - self.free.len();
+        log(&format!("receivers in flight: {registered}"));
+    }
+
+    /// Applies `actions`, including the ones queued by `on_register` along
+    /// the way, and returns whether the loop should exit.
+    fn apply(&mut self, actions: &mut InterestActions) -> std::io::Result<bool> {
         let mut exit = false;
-        for action in actions {
+        while let Some(action) = actions.next() {
             match action {
                 InterestAction::Add(fd, flags, receiver) => {
-                    self.add_interest(fd, flags, receiver)?;
+                    if let Err(e) = self.register(fd, flags, true, receiver, actions) {
+                        if self.fault_policy.is_fatal(&e) {
+                            return Err(e);
+                        }
+                        log_error(&format!("could not register fd {fd}: {e}"));
+                        let _ = unsafe { libc::close(fd) };
+                    }
                 }
-                InterestAction::Modify(fd, flags) => self.modify_interest(fd, flags)?,
-                InterestAction::Remove(fd) => self.remove_interest(fd)?,
+                InterestAction::Modify(token, flags) => {
+                    if let Err(e) = self.modify_interest(token, flags) {
+                        self.isolate(token, e, actions)?;
+                    }
+                }
+                InterestAction::Remove(token) => {
+                    if let Err(e) = self.remove_interest(token, UnregisterReason::Removed, actions)
+                    {
+                        self.isolate(token, e, actions)?;
+                    }
+                }
+                InterestAction::Timer(token, key, op) => {
+                    if self.registration(token).is_some() {
+                        self.timers.apply(token, key, op);
+                    }
+                }
+                InterestAction::Signal(event) => self.signal(&event, actions)?,
                 InterestAction::Exit => {
                     exit = true;
                 }
-                InterestAction::PrintStats => {
-                    log(&format!("receivers in flight: {}", self.receivers.len()));
-                }
+                InterestAction::PrintStats => self.print_stats(),
             }
         }
         Ok(exit)
     }
 
     pub(crate) fn run(&mut self, verbose: bool) -> std::io::Result<()> {
-        let mut events: Vec<libc::epoll_event> = Vec::with_capacity(1024);
-        loop {
-            // TODO: avoid allocation in a loop
-            let mut interest_actions = InterestActions::new();
-            events.clear();
-            let res = match syscall!(epoll_wait(self.epoll_fd, events.as_mut_ptr(), 1024, -1,)) {
-                Ok(v) => v,
-                Err(e) => panic!("error during epoll wait: {e}"),
-            };
+        while !self.run_once(verbose)? {}
+        Ok(())
+    }
 
-            #[allow(clippy::cast_sign_loss)]
-            unsafe {
-                events.set_len(res as usize);
-            };
+    /// Waits for and handles one batch of events and due timers. Returns
+    /// whether the loop should exit.
+    pub(crate) fn run_once(&mut self, verbose: bool) -> std::io::Result<bool> {
+        let mut events = std::mem::take(&mut self.events);
+        let mut interest_actions = std::mem::take(&mut self.actions);
+        let timeout = self.timers.timeout(Instant::now());
+        match self.backend.wait(&mut events, timeout) {
+            // a signal cut the wait short; due timers still fire below
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => {}
+            result => result?,
+        }
 
-            for ev in &events {
-                let fd = ev.u64 as RawFd;
-                #[allow(clippy::cast_possible_wrap)]
-                let ready_to = State(ev.events as i32);
-                if ready_to.action() {
-                    match self.receivers.get(&fd) {
-                        Some(receiver) => {
-                            receiver
-                                .borrow_mut()
-                                .on_ready(ready_to, fd, &mut interest_actions)?;
-                        }
-                        None => {
-                            if verbose {
-                                log(&format!("unexpected fd {fd} for EPOLLIN"));
-                            }
-                        }
+        let result = self.dispatch(&mut events, &mut interest_actions, verbose);
+        self.events = events;
+        self.actions = interest_actions;
+        result
+    }
+
+    /// Hands the ready `events` and due timers to their receivers and
+    /// applies what they ask for.
+    fn dispatch(
+        &mut self,
+        events: &mut Vec<(Token, State)>,
+        interest_actions: &mut InterestActions,
+        verbose: bool,
+    ) -> std::io::Result<bool> {
+        for (token, ready_to) in events.drain(..) {
+            let Some((fd, receiver)) = self.registration(token).map(|r| (r.fd, r.receiver.clone()))
+            else {
+                // the fd went away earlier in this batch
+                if verbose {
+                    log("dropped event for a stale token");
+                }
+                continue;
+            };
+            let result = if ready_to.action() {
+                receiver
+                    .borrow_mut()
+                    .on_ready(ready_to, fd, token, interest_actions)
+            } else if ready_to.shutdown() {
+                if ready_to.error() {
+                    let error = pending_error(fd);
+                    if let Err(e) =
+                        receiver
+                            .borrow_mut()
+                            .on_error(fd, token, &error, interest_actions)
+                    {
+                        log_error(&format!("error while handling an error of fd {fd}: {e}"));
                     }
-                } else if ready_to.shutdown() {
-                    self.remove_interest(fd)?;
                 }
+                self.remove_interest(token, UnregisterReason::HungUp, interest_actions)
+            } else {
+                Ok(())
+            };
+            if let Err(e) = result {
+                self.isolate(token, e, interest_actions)?;
             }
-            if self.apply(interest_actions)? {
-                break Ok(());
+        }
+        let now = Instant::now();
+        while let Some((token, key)) = self.timers.pop_due(now) {
+            let Some(receiver) = self.registration(token).map(|r| r.receiver.clone()) else {
+                continue;
+            };
+            let fired = receiver.borrow_mut().on_timer(token, key, interest_actions);
+            if let Err(e) = fired {
+                self.isolate(token, e, interest_actions)?;
             }
         }
+        self.apply(interest_actions)
     }
 }
 
 impl Drop for Reactor {
     fn drop(&mut self) {
-        for (fd, _receiver) in self.receivers.drain() {
-            // TODO: do we need on_unregister() callback
-            // TODO: code duplication for syscall
-            let _ = syscall!(epoll_ctl(
-                self.epoll_fd,
-                libc::EPOLL_CTL_DEL,
+        for (index, slot) in self.slots.drain(..).enumerate() {
+            let Some(Registration {
                 fd,
-                std::ptr::null_mut()
-            ));
+                owned,
+                receiver,
+            }) = slot.registration
+            else {
+                continue;
+            };
+            #[allow(clippy::cast_possible_truncation)]
+            let token = Token {
+                index: index as u32,
+                generation: slot.generation,
+            };
+            let _ = self.backend.remove(fd, token);
+            // nothing is left to apply the actions
+            let mut actions = InterestActions::new();
+            let _ = receiver.borrow_mut().on_unregister(
+                fd,
+                token,
+                UnregisterReason::Shutdown,
+                &mut actions,
+            );
+            if owned {
+                let _ = unsafe { libc::close(fd) };
+            }
         }
     }
 }
+
+/// Takes the error pending on `fd`, which is a socket in most cases.
+fn pending_error(fd: RawFd) -> std::io::Error {
+    let mut error: libc::c_int = 0;
+    #[allow(clippy::cast_possible_truncation)]
+    let mut len = size_of::<libc::c_int>() as libc::socklen_t;
+    let res = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_ERROR,
+            (&raw mut error).cast(),
+            &raw mut len,
+        )
+    };
+    if res == 0 && error != 0 {
+        std::io::Error::from_raw_os_error(error)
+    } else {
+        std::io::Error::other("error condition on fd")
+    }
+}
@@ -1,13 +1,28 @@
 use std::cell::RefCell;
-use std::net::TcpListener;
+use std::ffi::OsString;
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV6};
 use std::ops::Not;
 use std::os::fd::IntoRawFd;
+use std::os::raw::c_void;
+use std::os::unix::ffi::OsStringExt;
+use std::os::unix::fs::FileTypeExt;
 use std::os::unix::io::RawFd;
+use std::os::unix::net::UnixListener;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::Duration;
 
-use crate::reactor::{EventReceiver, InterestAction, InterestActions, State, READ};
+use crate::config::ListenAddr;
+use crate::reactor::{EventReceiver, InterestAction, InterestActions, State, Token, READ};
 use crate::request_context::RequestContext;
-use crate::{log, syscall};
+use crate::signal::SignalEvent;
+use crate::timer_queue::TimerOp;
+use crate::{log, log_error, syscall};
+
+/// Pause before accepting again after `accept` failed for lack of file
+/// descriptors or memory, which a pending connection would otherwise
+/// report again right away, or while at the connection limit.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
 
 fn set_nonblocking(fd: RawFd, nonblocking: bool) -> std::io::Result<()> {
     // The only difference of O_NONBLOCKING occurs when no data is present
@@ -23,24 +38,168 @@ fn set_nonblocking(fd: RawFd, nonblocking: bool) -> std::io::Result<()> {
     Ok(())
 }
 
+/// Binds the listening socket of a worker to `addr`, with room for
+/// `backlog` pending connections. With `reuse_port`, other workers may
+/// have their own socket on the same address, and the kernel spreads
+/// incoming connections among them.
+#[allow(clippy::cast_possible_truncation)]
+pub(crate) fn bind(
+    addr: SocketAddr,
+    reuse_port: bool,
+    backlog: libc::c_int,
+) -> std::io::Result<RawFd> {
+    let (family, storage, len) = sockaddr(addr);
+    let fd = syscall!(socket(family, libc::SOCK_STREAM | libc::SOCK_CLOEXEC, 0))?;
+    let bound = (|| {
+        let on: libc::c_int = 1;
+        let options: &[_] = if reuse_port {
+            &[libc::SO_REUSEADDR, libc::SO_REUSEPORT]
+        } else {
+            &[libc::SO_REUSEADDR]
+        };
+        for &option in options {
+            syscall!(setsockopt(
+                fd,
+                libc::SOL_SOCKET,
+                option,
+                (&raw const on).cast::<c_void>(),
+                size_of::<libc::c_int>() as libc::socklen_t
+            ))?;
+        }
+        syscall!(bind(fd, (&raw const storage).cast::<libc::sockaddr>(), len))?;
+        syscall!(listen(fd, backlog))?;
+        Ok(fd)
+    })();
+    if bound.is_err() {
+        let _ = unsafe { libc::close(fd) };
+    }
+    bound
+}
+
+/// Binds a Unix socket at `path`, which all workers share, with room for
+/// `backlog` pending connections. A socket file left at `path` by an
+/// earlier run is replaced.
+pub(crate) fn bind_unix(path: &Path, backlog: libc::c_int) -> std::io::Result<RawFd> {
+    if std::fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(path)?;
+    }
+    let fd = UnixListener::bind(path)?.into_raw_fd();
+    // listening again only changes the backlog
+    if let Err(e) = syscall!(listen(fd, backlog)) {
+        let _ = unsafe { libc::close(fd) };
+        return Err(e);
+    }
+    Ok(fd)
+}
+
+#[allow(clippy::cast_possible_truncation)]
+fn sockaddr(addr: SocketAddr) -> (libc::c_int, libc::sockaddr_storage, libc::socklen_t) {
+    let mut storage: libc::sockaddr_storage = unsafe { std::mem::zeroed() };
+    let len = match addr {
+        SocketAddr::V4(v4) => {
+            let sin = libc::sockaddr_in {
+                sin_family: libc::AF_INET as libc::sa_family_t,
+                sin_port: v4.port().to_be(),
+                sin_addr: libc::in_addr {
+                    s_addr: u32::from(*v4.ip()).to_be(),
+                },
+                sin_zero: [0; 8],
+            };
+            unsafe { (&raw mut storage).cast::<libc::sockaddr_in>().write(sin) };
+            size_of::<libc::sockaddr_in>()
+        }
+        SocketAddr::V6(v6) => {
+            let sin6 = libc::sockaddr_in6 {
+                sin6_family: libc::AF_INET6 as libc::sa_family_t,
+                sin6_port: v6.port().to_be(),
+                sin6_flowinfo: v6.flowinfo(),
+                sin6_addr: libc::in6_addr {
+                    s6_addr: v6.ip().octets(),
+                },
+                sin6_scope_id: v6.scope_id(),
+            };
+            unsafe { (&raw mut storage).cast::<libc::sockaddr_in6>().write(sin6) };
+            size_of::<libc::sockaddr_in6>()
+        }
+    };
+    (
+        libc::c_int::from(storage.ss_family),
+        storage,
+        len as libc::socklen_t,
+    )
+}
+
+/// Address the listening socket `fd` is bound to, which tells its
+/// listener whichever way the socket was opened.
+pub(crate) fn local_addr(fd: RawFd) -> std::io::Result<ListenAddr> {
+    let mut storage = unsafe { std::mem::zeroed::<libc::sockaddr_storage>() };
+    #[allow(clippy::cast_possible_truncation)]
+    let mut len = size_of::<libc::sockaddr_storage>() as libc::socklen_t;
+    syscall!(getsockname(
+        fd,
+        (&raw mut storage).cast::<libc::sockaddr>(),
+        &raw mut len
+    ))?;
+    match libc::c_int::from(storage.ss_family) {
+        libc::AF_INET => {
+            let sin = unsafe { (&raw const storage).cast::<libc::sockaddr_in>().read() };
+            let ip = Ipv4Addr::from(u32::from_be(sin.sin_addr.s_addr));
+            let port = u16::from_be(sin.sin_port);
+            Ok(ListenAddr::Tcp(SocketAddr::from((ip, port))))
+        }
+        libc::AF_INET6 => {
+            let sin6 = unsafe { (&raw const storage).cast::<libc::sockaddr_in6>().read() };
+            let ip = Ipv6Addr::from(sin6.sin6_addr.s6_addr);
+            let port = u16::from_be(sin6.sin6_port);
+            Ok(ListenAddr::Tcp(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                port,
+                sin6.sin6_flowinfo,
+                sin6.sin6_scope_id,
+            ))))
+        }
+        libc::AF_UNIX => {
+            let sun = unsafe { (&raw const storage).cast::<libc::sockaddr_un>().read() };
+            // up to the terminating NUL; an abstract address has no path
+            #[allow(clippy::cast_sign_loss)]
+            let path: Vec<u8> = sun
+                .sun_path
+                .iter()
+                .take_while(|&&c| c != 0)
+                .map(|&c| c as u8)
+                .collect();
+            Ok(ListenAddr::Unix(PathBuf::from(OsString::from_vec(path))))
+        }
+        family => Err(std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("fd {fd} is a socket of unsupported family {family}"),
+        )),
+    }
+}
+
 pub struct Listener {
     fd: RawFd,
     verbose: bool,
     req_actor: Rc<RefCell<RequestContext>>,
+    /// Index of the socket among those of the worker, which decides the
+    /// settings and routes of its connections.
+    site: usize,
 }
 
 impl Listener {
+    /// Accepts on the listening socket `fd`, which it takes over.
     pub(crate) fn new(
         verbose: bool,
         req_actor: Rc<RefCell<RequestContext>>,
+        fd: RawFd,
+        site: usize,
     ) -> std::io::Result<Self> {
-        let listener = TcpListener::bind("127.0.0.1:8000")?;
-        let fd = listener.into_raw_fd();
         set_nonblocking(fd, true)?;
         Ok(Self {
             fd,
             verbose,
             req_actor,
+            site,
         })
     }
 
@@ -59,20 +218,83 @@ impl EventReceiver for Listener {
         &mut self,
         ready_to: State,
         fd: RawFd,
+        token: Token,
         new_actions: &mut InterestActions,
     ) -> std::io::Result<()> {
         debug_assert!(ready_to.read());
-        let accepted_socket = syscall!(accept(fd, std::ptr::null_mut(), std::ptr::null_mut()))?;
+        if self.req_actor.borrow().is_full(self.site) {
+            // the pending connections wait in the backlog meanwhile
+            new_actions.add(InterestAction::Timer(
+                token,
+                0,
+                TimerOp::Once(ACCEPT_BACKOFF),
+            ));
+            return Ok(());
+        }
+        // not inherited by a new process on an upgrade, which would keep
+        // the connection open after this one is gone
+        let accepted = syscall!(accept4(
+            fd,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+            libc::SOCK_NONBLOCK | libc::SOCK_CLOEXEC
+        ));
+        let accepted_socket = match accepted {
+            Ok(accepted_socket) => accepted_socket,
+            Err(e) => {
+                match e.kind() {
+                    // another worker took it, or the client gave up
+                    std::io::ErrorKind::WouldBlock
+                    | std::io::ErrorKind::ConnectionAborted
+                    | std::io::ErrorKind::Interrupted => {
+                        new_actions.add(InterestAction::Modify(token, READ));
+                    }
+                    // the listener itself is fine, so it is kept
+                    _ => {
+                        log_error(&format!("could not accept on fd {fd}: {e}"));
+                        new_actions.add(InterestAction::Timer(
+                            token,
+                            0,
+                            TimerOp::Once(ACCEPT_BACKOFF),
+                        ));
+                    }
+                }
+                return Ok(());
+            }
+        };
         if self.verbose {
             log(&format!("new client fd: {accepted_socket}"));
         }
-        set_nonblocking(accepted_socket, true)?;
+        new_actions.add(InterestAction::Modify(token, READ));
+        self.req_actor
+            .borrow_mut()
+            .admit(accepted_socket, self.site);
         new_actions.add(InterestAction::Add(
             accepted_socket,
             READ,
             self.req_actor.clone(),
         ));
-        new_actions.add(InterestAction::Modify(fd, READ));
+        Ok(())
+    }
+
+    fn on_timer(
+        &mut self,
+        token: Token,
+        _key: u64,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        new_actions.add(InterestAction::Modify(token, READ));
+        Ok(())
+    }
+
+    fn on_signal(
+        &mut self,
+        event: &SignalEvent,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if let SignalEvent::Reload(config) = event {
+            self.verbose = config.verbose();
+        }
         Ok(())
     }
 }
@@ -1,34 +1,279 @@
 use std::cell::RefCell;
 use std::collections::{HashMap, VecDeque};
+use std::fs;
 use std::mem::MaybeUninit;
 use std::os::fd::RawFd;
 use std::os::raw::c_void;
+use std::path::{Path, PathBuf};
 use std::rc::Rc;
+use std::time::Duration;
 
+use crate::config::{Config, ListenAddr};
 use crate::content_actor::Handle as ContentHandle;
 use crate::content_actor::Message as ContentMessage;
-use crate::reactor::{EventReceiver, InterestAction, InterestActions, Reactor, State, READ, WRITE};
+use crate::http::{self, Framing, Request, Response};
+use crate::multipart::{self, Form};
+use crate::out_buffer::OutBuffer;
+use crate::reactor::{
+    EventReceiver, InterestAction, InterestActions, Reactor, State, Token, UnregisterReason, READ,
+    WRITE,
+};
+use crate::router::{Body, RouteError, Router};
+use crate::signal::SignalEvent;
+use crate::timer_queue::TimerOp;
+use crate::upload::Upload;
 use crate::{log, syscall};
 
-const HTTP_RESP: &[u8] = br"HTTP/1.1 200 OK
-content-type: text/html
-content-length: 5
+enum Stage {
+    /// Waiting for a complete request head.
+    Head,
+    /// The head went to the content actor to find out the body framing.
+    Framing,
+    /// Streaming the body to disk, with the given number of bytes to go.
+    /// Anything past that belongs to the next pipelined request.
+    Body(usize),
+    /// Streaming a chunked body to disk as it is decoded.
+    Chunked(http::ChunkedDecoder),
+    /// Waiting for the socket to become writable to send `out`.
+    Respond,
+}
+
+/// Where the body of the current request goes.
+enum Sink {
+    File(Upload),
+    Form(Box<multipart::Parser>),
+}
+
+/// Fully received body, as kept while its handler runs.
+enum Stored {
+    Empty,
+    File(PathBuf),
+    Form(Form),
+}
+
+impl Stored {
+    fn body(&self) -> Body<'_> {
+        match self {
+            Stored::Empty => Body::Empty,
+            Stored::File(path) => Body::File(path),
+            Stored::Form(form) => Body::Form(form),
+        }
+    }
+
+    fn remove(&self) {
+        match self {
+            Stored::Empty => {}
+            Stored::File(path) => {
+                let _ = fs::remove_file(path);
+            }
+            Stored::Form(form) => form.remove_files(),
+        }
+    }
+}
+
+/// Key of the reactor timer that holds a connection's deadline.
+const DEADLINE: u64 = 0;
+/// Closed connections kept for reuse along with their buffers.
+const MAX_POOLED: usize = 256;
+
+/// What a connection is waiting for when its deadline passes.
+#[derive(Clone, Copy)]
+enum Timeout {
+    /// The rest of the request head, answered with 408.
+    Header,
+    /// More of the body, answered with 408.
+    Body,
+    /// The next request on a kept-alive connection.
+    Idle,
+    /// The peer to accept more of the response.
+    Write,
+}
+
+impl Timeout {
+    fn after(self, config: &Config) -> Duration {
+        match self {
+            Timeout::Header => config.header_timeout,
+            Timeout::Body => config.body_timeout,
+            Timeout::Idle => config.keep_alive_timeout,
+            Timeout::Write => config.write_timeout,
+        }
+    }
+}
+
+struct Connection {
+    fd: RawFd,
+    buf: Vec<u8>,
+    parser: http::Parser,
+    request: Request,
+    stage: Stage,
+    /// Response waiting to be written.
+    out: OutBuffer,
+    /// Body of the current request. A multipart body gets its parser along
+    /// with the head; any other is written to a file created with its
+    /// first byte.
+    sink: Option<Sink>,
+    /// Body bytes received for the current request.
+    received: usize,
+    /// Bytes of a chunked body consumed so far, framing and trailers
+    /// included.
+    encoded: usize,
+    /// Requests answered so far, bounded by `max_requests_per_connection`.
+    served: usize,
+    keep_alive: bool,
+    /// What the deadline timer of the connection is armed for.
+    timeout: Option<Timeout>,
+    /// Index of the listener that accepted it in `RequestContext::sites`.
+    site: usize,
+}
+
+impl Connection {
+    fn new(fd: RawFd) -> Self {
+        Self {
+            fd,
+            buf: Vec::with_capacity(32),
+            parser: http::Parser::default(),
+            request: Request::default(),
+            stage: Stage::Head,
+            out: OutBuffer::default(),
+            sink: None,
+            received: 0,
+            encoded: 0,
+            served: 0,
+            keep_alive: false,
+            timeout: None,
+            site: 0,
+        }
+    }
+
+    /// Replaces the deadline of the connection registered as `token`.
+    fn arm(
+        &mut self,
+        token: Token,
+        timeout: Timeout,
+        config: &Config,
+        new_actions: &mut InterestActions,
+    ) {
+        self.timeout = Some(timeout);
+        let op = TimerOp::Once(timeout.after(config));
+        new_actions.add(InterestAction::Timer(token, DEADLINE, op));
+    }
+
+    fn disarm(&mut self, token: Token, new_actions: &mut InterestActions) {
+        self.timeout = None;
+        new_actions.add(InterestAction::Timer(token, DEADLINE, TimerOp::Cancel));
+    }
+
+    /// Passes the first `len` bytes of `buf` on to the body sink. The caller
+    /// drains them afterwards.
+    fn store(&mut self, len: usize, dir: &Path) -> std::io::Result<()> {
+        if len == 0 {
+            return Ok(());
+        }
+        let data = &self.buf[..len];
+        match &mut self.sink {
+            Some(Sink::File(upload)) => upload.write(data)?,
+            Some(Sink::Form(parser)) => parser.feed(data)?,
+            None => {
+                let mut upload = Upload::create(dir)?;
+                upload.write(data)?;
+                self.sink = Some(Sink::File(upload));
+            }
+        }
+        self.received += len;
+        Ok(())
+    }
+
+    /// Marks the request as fully received and runs its handler.
+    fn complete(&mut self, max_requests: usize, router: &Router) -> std::io::Result<()> {
+        self.served += 1;
+        self.keep_alive = self.request.keep_alive() && self.served < max_requests;
+        let stored = match self.sink.take() {
+            None => Stored::Empty,
+            Some(Sink::File(upload)) => Stored::File(upload.finish()?),
+            Some(Sink::Form(parser)) => Stored::Form(parser.finish()?),
+        };
+        let resp = match router.route(&self.request) {
+            Ok((handler, params)) => handler.handle(&self.request, &params, &stored.body()),
+            Err(RouteError::NotFound) => Response::new(404),
+            Err(RouteError::MethodNotAllowed(allowed)) => {
+                Response::new(405).with_header("allow", allowed.join(", "))
+            }
+        };
+        // whatever the handler did not move elsewhere
+        stored.remove();
+        let head_only = self.request.method() == "HEAD";
+        self.respond(resp, head_only);
+        Ok(())
+    }
+
+    /// Queues `resp` for writing. The body is left out for responses to
+    /// `HEAD` requests, but still counted in `content-length`.
+    fn respond(&mut self, resp: Response, head_only: bool) {
+        resp.write_head(self.out.start(), self.keep_alive);
+        if !head_only {
+            self.out.set_body(resp.into_body());
+        }
+        self.stage = Stage::Respond;
+    }
+
+    /// Makes a closed connection ready to serve `fd`, keeping the capacity
+    /// of its buffers. Any body in progress is dropped and removed.
+    fn recycle(&mut self, fd: RawFd) {
+        self.fd = fd;
+        self.buf.clear();
+        self.out.start();
+        self.sink = None;
+        self.served = 0;
+        self.keep_alive = false;
+        self.timeout = None;
+        self.reset();
+    }
 
-Hello";
+    /// Prepares a kept-alive connection for the next request. Any request
+    /// pipelined behind the current one stays in `buf`.
+    fn reset(&mut self) {
+        self.received = 0;
+        self.encoded = 0;
+        self.parser = http::Parser::default();
+        self.stage = Stage::Head;
+    }
+}
+
+/// Settings and routes of the connections of one listener.
+struct Site {
+    /// Address of the listening socket, which finds its settings.
+    address: ListenAddr,
+    config: Config,
+    router: Router,
+    /// Connections it has open.
+    connections: usize,
+}
 
 pub struct RequestContext {
-    buf: HashMap<RawFd, Vec<u8>>,
+    conns: HashMap<Token, Connection>,
+    pool: Vec<Connection>,
     verbose: bool,
+    /// One for each listening socket of the worker.
+    sites: Vec<Site>,
+    /// Listener of each accepted fd until it is registered.
+    admitted: Vec<(RawFd, usize)>,
+    /// Builds the router again once the configuration is reloaded.
+    routes: fn(&Config) -> Router,
     efd: RawFd,
     ctr_queue: Rc<RefCell<VecDeque<Message>>>,
     content_handle: ContentHandle,
-    content_length: RefCell<HashMap<RawFd, usize>>,
+    /// Set once a shutdown starts: no request is kept alive after this.
+    draining: bool,
 }
 
 pub enum Message {
-    ContentLengthResponse {
-        receiver: RawFd,
-        content_length: usize,
+    /// Closes the idle connections, lets the others finish their request
+    /// and stops the reactor once none are left.
+    Drain,
+    BodyFramingResponse {
+        receiver: Token,
+        req: Request,
+        framing: Result<Framing, http::Error>,
     },
 }
 
@@ -36,109 +281,443 @@ impl RequestContext {
     fn new(
         ctr_queue: Rc<RefCell<VecDeque<Message>>>,
         efd: RawFd,
-        verbose: bool,
+        config: &Config,
+        addresses: Vec<ListenAddr>,
+        routes: fn(&Config) -> Router,
         content_handle: ContentHandle,
     ) -> Self {
+        let sites = addresses
+            .into_iter()
+            .map(|address| {
+                let config = config.site(&address).clone();
+                Site {
+                    address,
+                    router: routes(&config),
+                    config,
+                    connections: 0,
+                }
+            })
+            .collect();
         Self {
-            buf: HashMap::new(),
-            verbose,
+            conns: HashMap::new(),
+            pool: Vec::new(),
+            verbose: config.verbose(),
+            sites,
+            admitted: Vec::new(),
+            routes,
             ctr_queue,
             efd,
             content_handle,
-            content_length: RefCell::new(HashMap::new()),
+            draining: false,
+        }
+    }
+
+    /// Answers or drops the connection of `token` once its deadline has
+    /// passed.
+    fn expire(&mut self, token: Token, new_actions: &mut InterestActions) {
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        let Some(timeout) = conn.timeout.take() else {
+            return;
+        };
+        if self.verbose {
+            log(&format!("fd {} timed out", conn.fd));
+        }
+        match timeout {
+            Timeout::Header | Timeout::Body => {
+                self.reject(token, http::Error::Timeout, new_actions);
+            }
+            Timeout::Idle | Timeout::Write => self.close(token, new_actions),
         }
     }
 
-    fn handle_message(&self, msg: &Message, new_actions: &mut InterestActions) {
+    fn handle_message(
+        &mut self,
+        msg: Message,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
         match msg {
-            Message::ContentLengthResponse {
+            Message::Drain => {
+                self.draining = true;
+                let idle: Vec<Token> = self
+                    .conns
+                    .iter()
+                    .filter(|(_, conn)| matches!(conn.stage, Stage::Head) && conn.buf.is_empty())
+                    .map(|(token, _)| *token)
+                    .collect();
+                for token in idle {
+                    self.close(token, new_actions);
+                }
+                if self.conns.is_empty() {
+                    new_actions.add(InterestAction::Exit);
+                }
+            }
+            Message::BodyFramingResponse {
                 receiver,
-                content_length,
+                req,
+                framing,
             } => {
-                self.content_length
-                    .borrow_mut()
-                    .insert(*receiver, *content_length);
-                self.check_length(*receiver, *content_length, new_actions);
+                // the connection may have been closed meanwhile
+                let Some(conn) = self.conns.get_mut(&receiver) else {
+                    return Ok(());
+                };
+                conn.request = req;
+                match framing {
+                    Ok(framing) => {
+                        conn.sink = multipart::boundary(&conn.request).map(|boundary| {
+                            Sink::Form(Box::new(multipart::Parser::new(
+                                boundary,
+                                &self.sites[conn.site].config.upload_dir,
+                            )))
+                        });
+                        conn.stage = match framing {
+                            Framing::Length(length)
+                                if length > self.sites[conn.site].config.max_body_size =>
+                            {
+                                self.reject(receiver, http::Error::BodyTooLarge, new_actions);
+                                return Ok(());
+                            }
+                            Framing::Length(length) => {
+                                conn.arm(
+                                    receiver,
+                                    Timeout::Body,
+                                    &self.sites[conn.site].config,
+                                    new_actions,
+                                );
+                                Stage::Body(length)
+                            }
+                            Framing::Chunked => {
+                                conn.arm(
+                                    receiver,
+                                    Timeout::Body,
+                                    &self.sites[conn.site].config,
+                                    new_actions,
+                                );
+                                Stage::Chunked(http::ChunkedDecoder::default())
+                            }
+                        };
+                        self.advance(receiver, new_actions)?;
+                    }
+                    Err(e) => self.reject(receiver, e, new_actions),
+                }
             }
         }
+        Ok(())
     }
 
-    fn check_length(&self, fd: RawFd, length: usize, new_actions: &mut InterestActions) {
-        match self.buf.get(&fd) {
-            Some(buf) => {
-                if buf.len() >= length {
-                    if self.verbose {
-                        log(&format!("got all data: {} bytes", buf.len()));
-                    }
-                    new_actions.add(InterestAction::Modify(fd, WRITE));
-                } else {
-                    new_actions.add(InterestAction::Modify(fd, READ));
+    /// Moves the request of `token` forward with the data buffered so far.
+    fn advance(&mut self, token: Token, new_actions: &mut InterestActions) -> std::io::Result<()> {
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return Ok(());
+        };
+        match &mut conn.stage {
+            Stage::Head => match conn.parser.parse(&conn.buf, &mut conn.request) {
+                Ok(Some(len))
+                    if len > self.sites[conn.site].config.max_header_size
+                        || conn.request.headers().count()
+                            > self.sites[conn.site].config.max_header_count =>
+                {
+                    self.reject(token, http::Error::HeadTooLarge, new_actions);
+                }
+                Ok(None) if conn.buf.len() > self.sites[conn.site].config.max_header_size => {
+                    self.reject(token, http::Error::HeadTooLarge, new_actions);
+                }
+                Ok(Some(len)) => {
+                    conn.buf.drain(..len);
+                    conn.stage = Stage::Framing;
+                    conn.disarm(token, new_actions);
+                    // fd is re-armed once the content actor answers
+                    self.content_handle
+                        .enqueue(ContentMessage::BodyFramingRequest {
+                            req: std::mem::take(&mut conn.request),
+                            sender: token,
+                        })?;
+                }
+                Ok(None) => new_actions.add(InterestAction::Modify(token, READ)),
+                Err(e) => self.reject(token, e, new_actions),
+            },
+            Stage::Body(remaining) => {
+                let len = (*remaining).min(conn.buf.len());
+                *remaining -= len;
+                let done = *remaining == 0;
+                let stored = conn.store(len, &self.sites[conn.site].config.upload_dir);
+                conn.buf.drain(..len);
+                match stored {
+                    Err(e) => self.fail(token, &e, new_actions),
+                    Ok(()) if done => self.finish(token, new_actions),
+                    Ok(()) => new_actions.add(InterestAction::Modify(token, READ)),
                 }
             }
-            None => {
-                if self.verbose {
-                    log(&format!("unexpected fd {fd}"));
+            Stage::Chunked(decoder) => {
+                let mut result = decoder.decode(&mut conn.buf);
+                let (len, consumed) = decoder.take();
+                conn.encoded += consumed;
+                let config = &self.sites[conn.site].config;
+                // the framing gets the room of a request head on top of the
+                // data, counting an unfinished line as well
+                let pending = if result == Ok(false) {
+                    conn.buf.len() - consumed
+                } else {
+                    0
+                };
+                if conn.received + len > config.max_body_size
+                    || conn.encoded + pending > config.max_body_size + config.max_header_size
+                {
+                    result = Err(http::Error::BodyTooLarge);
+                }
+                let stored = match result {
+                    Ok(_) => conn.store(len, &config.upload_dir),
+                    Err(_) => Ok(()),
+                };
+                conn.buf.drain(..consumed);
+                match (result, stored) {
+                    (Err(e), _) => self.reject(token, e, new_actions),
+                    (_, Err(e)) => self.fail(token, &e, new_actions),
+                    (Ok(true), Ok(())) => self.finish(token, new_actions),
+                    (Ok(false), Ok(())) => new_actions.add(InterestAction::Modify(token, READ)),
                 }
             }
+            Stage::Framing | Stage::Respond => {}
+        }
+        Ok(())
+    }
+
+    /// Runs the handler once the whole request is in.
+    fn finish(&mut self, token: Token, new_actions: &mut InterestActions) {
+        let Some(conn) = self.conns.get_mut(&token) else {
+            return;
+        };
+        if self.verbose {
+            log(&format!("got all data: {} bytes", conn.received));
+        }
+        // a draining connection answers with `connection: close`
+        let max_requests = if self.draining {
+            0
+        } else {
+            self.sites[conn.site].config.max_requests_per_connection
+        };
+        match conn.complete(max_requests, &self.sites[conn.site].router) {
+            Ok(()) => {
+                conn.arm(
+                    token,
+                    Timeout::Write,
+                    &self.sites[conn.site].config,
+                    new_actions,
+                );
+                new_actions.add(InterestAction::Modify(token, WRITE));
+            }
+            Err(e) => self.fail(token, &e, new_actions),
+        }
+    }
+
+    /// Answers with 400 for a malformed body and 500 for a local error,
+    /// then closes the connection.
+    fn fail(&mut self, token: Token, e: &std::io::Error, new_actions: &mut InterestActions) {
+        if let Some(conn) = self.conns.get_mut(&token) {
+            if self.verbose {
+                log(&format!("could not handle request on fd {}: {e}", conn.fd));
+            }
+            let status = match e.kind() {
+                std::io::ErrorKind::InvalidData => 400,
+                _ => 500,
+            };
+            conn.sink = None;
+            conn.keep_alive = false;
+            conn.respond(Response::new(status), false);
+            conn.arm(
+                token,
+                Timeout::Write,
+                &self.sites[conn.site].config,
+                new_actions,
+            );
+            new_actions.add(InterestAction::Modify(token, WRITE));
+        }
+    }
+
+    fn reject(&mut self, token: Token, e: http::Error, new_actions: &mut InterestActions) {
+        if let Some(conn) = self.conns.get_mut(&token) {
+            if self.verbose {
+                log(&format!("bad request on fd {}: {e}", conn.fd));
+            }
+            conn.sink = None;
+            conn.keep_alive = false;
+            conn.respond(Response::new(e.status()), false);
+            conn.arm(
+                token,
+                Timeout::Write,
+                &self.sites[conn.site].config,
+                new_actions,
+            );
+            new_actions.add(InterestAction::Modify(token, WRITE));
+        }
+    }
+
+    fn close(&mut self, token: Token, new_actions: &mut InterestActions) {
+        // later events of this turn are ignored
+        self.release(token, new_actions);
+        new_actions.add(InterestAction::Remove(token));
+    }
+
+    /// Whether listener `site` serves as many connections as it may.
+    pub(crate) fn is_full(&self, site: usize) -> bool {
+        let site = &self.sites[site];
+        site.connections >= site.config.max_connections
+    }
+
+    /// Notes that the connection on `fd`, about to be registered, was
+    /// accepted by listener `site`.
+    pub(crate) fn admit(&mut self, fd: RawFd, site: usize) {
+        // an fd whose registration failed may come again
+        self.admitted.retain(|(admitted, _)| *admitted != fd);
+        self.admitted.push((fd, site));
+    }
+
+    /// Forgets the connection of `token` and keeps its buffers for the next.
+    /// Stops the reactor once the last connection of a drain is gone.
+    fn release(&mut self, token: Token, new_actions: &mut InterestActions) {
+        if let Some(mut conn) = self.conns.remove(&token) {
+            self.sites[conn.site].connections -= 1;
+            // drop the body in progress, if any, right away
+            conn.sink = None;
+            if self.pool.len() < MAX_POOLED {
+                self.pool.push(conn);
+            }
+            if self.draining && self.conns.is_empty() {
+                new_actions.add(InterestAction::Exit);
+            }
         }
     }
 
-    fn on_read(&mut self, fd: RawFd, new_actions: &mut InterestActions) -> std::io::Result<()> {
+    fn on_read(
+        &mut self,
+        fd: RawFd,
+        token: Token,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
         if fd == self.efd {
             // Control message
             let mut value = MaybeUninit::<u64>::uninit();
             syscall!(eventfd_read(fd, value.as_mut_ptr()))?;
-            for msg in self.ctr_queue.borrow_mut().drain(..) {
-                self.handle_message(&msg, new_actions);
+            let ctr_queue = self.ctr_queue.clone();
+            for msg in ctr_queue.borrow_mut().drain(..) {
+                self.handle_message(msg, new_actions)?;
             }
-            new_actions.add(InterestAction::Modify(fd, READ));
+            new_actions.add(InterestAction::Modify(token, READ));
         } else {
             // TCP request
             let mut buf = [0u8; 4096];
             let res = unsafe { libc::read(fd, buf.as_mut_ptr().cast::<c_void>(), buf.len()) };
-            if res >= 0 {
-                #[allow(clippy::cast_sign_loss)]
-                let sz = res as usize;
-                self.buf
-                    .entry(fd)
-                    .or_insert_with(|| Vec::with_capacity(32))
-                    .extend_from_slice(&buf[..sz]);
-            } else if res != libc::EWOULDBLOCK as _ {
-                return Err(std::io::Error::last_os_error());
-            }
-
-            match self.content_length.borrow().get(&fd) {
-                None => {
-                    #[allow(clippy::cast_sign_loss)]
-                    let sz = res as usize;
-                    let data = String::from_utf8_lossy(&buf[..sz]).into_owned();
-                    self.content_handle
-                        .enqueue(ContentMessage::ContentLengthRequest {
-                            req: data,
-                            sender: fd,
-                        })?;
+            if res < 0 {
+                let e = std::io::Error::last_os_error();
+                match e.kind() {
+                    std::io::ErrorKind::WouldBlock => {
+                        new_actions.add(InterestAction::Modify(token, READ));
+                    }
+                    // the client went away, which is no fault of ours
+                    std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::BrokenPipe => {
+                        if self.verbose {
+                            log(&format!("fd {fd} reset by peer"));
+                        }
+                        self.close(token, new_actions);
+                    }
+                    _ => return Err(e),
+                }
+                return Ok(());
+            }
+            if res == 0 {
+                if self.verbose {
+                    log(&format!("fd {fd} closed by peer"));
+                }
+                self.close(token, new_actions);
+                return Ok(());
+            }
+            let Some(conn) = self.conns.get_mut(&token) else {
+                if self.verbose {
+                    log(&format!("unexpected fd {fd}"));
+                }
+                return Ok(());
+            };
+            #[allow(clippy::cast_sign_loss)]
+            let sz = res as usize;
+            conn.buf.extend_from_slice(&buf[..sz]);
+            match (&conn.stage, conn.timeout) {
+                // the next request on a kept-alive connection has started
+                (Stage::Head, Some(Timeout::Idle)) => {
+                    conn.arm(
+                        token,
+                        Timeout::Header,
+                        &self.sites[conn.site].config,
+                        new_actions,
+                    );
                 }
-                Some(length) => {
-                    self.check_length(fd, *length, new_actions);
+                (Stage::Body(_) | Stage::Chunked(_), _) => {
+                    conn.arm(
+                        token,
+                        Timeout::Body,
+                        &self.sites[conn.site].config,
+                        new_actions,
+                    );
                 }
+                _ => {}
             }
+            self.advance(token, new_actions)?;
         }
         Ok(())
     }
 
-    fn on_write(&mut self, fd: RawFd, new_actions: &mut InterestActions) {
-        let res = unsafe { libc::write(fd, HTTP_RESP.as_ptr().cast::<c_void>(), HTTP_RESP.len()) };
-        if res > 0 {
+    fn on_write(
+        &mut self,
+        fd: RawFd,
+        token: Token,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        let Some(conn) = self.conns.get_mut(&token) else {
             if self.verbose {
-                log(&format!("answered from fd {fd}"));
+                log(&format!("unexpected fd {fd}"));
             }
-        } else {
-            let e = std::io::Error::last_os_error();
-            if self.verbose {
-                log(&format!("could not answer to fd {fd}: {e}"));
+            return Ok(());
+        };
+        if !matches!(conn.stage, Stage::Respond) {
+            return Ok(());
+        }
+        match conn.out.flush(fd) {
+            Ok(false) => {
+                conn.arm(
+                    token,
+                    Timeout::Write,
+                    &self.sites[conn.site].config,
+                    new_actions,
+                );
+                new_actions.add(InterestAction::Modify(token, WRITE));
+                return Ok(());
+            }
+            Ok(true) => {
+                if self.verbose {
+                    log(&format!("answered from fd {fd}"));
+                }
+                // a reply sent before the drain started may have promised
+                // to keep the connection open
+                if conn.keep_alive && !self.draining {
+                    conn.reset();
+                    conn.arm(
+                        token,
+                        Timeout::Idle,
+                        &self.sites[conn.site].config,
+                        new_actions,
+                    );
+                    // a pipelined request may already be buffered
+                    return self.advance(token, new_actions);
+                }
+            }
+            Err(e) => {
+                if self.verbose {
+                    log(&format!("could not answer to fd {fd}: {e}"));
+                }
             }
         }
-        new_actions.add(InterestAction::Remove(fd));
+        self.close(token, new_actions);
+        Ok(())
     }
 }
 
@@ -147,15 +726,114 @@ impl EventReceiver for RequestContext {
         &mut self,
         ready_to: State,
         fd: RawFd,
+        token: Token,
         new_actions: &mut InterestActions,
     ) -> std::io::Result<()> {
         if ready_to.read() {
-            self.on_read(fd, new_actions)?;
+            self.on_read(fd, token, new_actions)?;
         } else if ready_to.write() {
-            self.on_write(fd, new_actions);
+            self.on_write(fd, token, new_actions)?;
+        }
+        Ok(())
+    }
+
+    /// Starts tracking a freshly accepted connection, so that it times out
+    /// even if it never sends anything.
+    fn on_register(
+        &mut self,
+        fd: RawFd,
+        token: Token,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if fd != self.efd {
+            let mut conn = match self.pool.pop() {
+                Some(mut conn) => {
+                    conn.recycle(fd);
+                    conn
+                }
+                None => Connection::new(fd),
+            };
+            conn.site = match self
+                .admitted
+                .iter()
+                .position(|(admitted, _)| *admitted == fd)
+            {
+                Some(index) => self.admitted.swap_remove(index).1,
+                None => 0,
+            };
+            self.sites[conn.site].connections += 1;
+            conn.arm(
+                token,
+                Timeout::Header,
+                &self.sites[conn.site].config,
+                new_actions,
+            );
+            self.conns.insert(token, conn);
+        }
+        Ok(())
+    }
+
+    fn on_unregister(
+        &mut self,
+        fd: RawFd,
+        token: Token,
+        reason: UnregisterReason,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if self.verbose && reason == UnregisterReason::HungUp {
+            log(&format!("fd {fd} hung up"));
+        }
+        self.release(token, new_actions);
+        Ok(())
+    }
+
+    fn on_error(
+        &mut self,
+        fd: RawFd,
+        _token: Token,
+        error: &std::io::Error,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if self.verbose {
+            log(&format!("error on fd {fd}: {error}"));
+        }
+        Ok(())
+    }
+
+    fn on_signal(
+        &mut self,
+        event: &SignalEvent,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        match event {
+            // connections keep the deadlines they have, the next ones and
+            // new requests follow the new configuration
+            SignalEvent::Reload(config) => {
+                for site in &mut self.sites {
+                    site.config = config.site(&site.address).clone();
+                    site.router = (self.routes)(&site.config);
+                }
+                self.verbose = config.verbose();
+            }
+            SignalEvent::DumpStats => log(&format!(
+                "connections: {}, pooled: {}, draining: {}",
+                self.conns.len(),
+                self.pool.len(),
+                self.draining
+            )),
         }
         Ok(())
     }
+
+    fn on_timer(
+        &mut self,
+        token: Token,
+        _key: u64,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        self.expire(token, new_actions);
+        Ok(())
+    }
 }
 
 #[derive(Clone)]
@@ -167,7 +845,10 @@ pub struct Handle {
 impl Handle {
     pub(crate) fn new() -> std::io::Result<Self> {
         let ctr_queue = Rc::new(RefCell::new(VecDeque::new()));
-        let efd = syscall!(eventfd(0, libc::EFD_SEMAPHORE | libc::EFD_NONBLOCK))?;
+        let efd = syscall!(eventfd(
+            0,
+            libc::EFD_SEMAPHORE | libc::EFD_NONBLOCK | libc::EFD_CLOEXEC
+        ))?;
 
         Ok(Self { efd, ctr_queue })
     }
@@ -178,16 +859,21 @@ impl Handle {
         Ok(())
     }
 
+    /// Binds the request context of a worker with `listeners` listeners.
     pub(crate) fn bind(
         &self,
         reactor: &mut Reactor,
-        verbose: bool,
+        config: &Config,
+        addresses: Vec<ListenAddr>,
+        routes: fn(&Config) -> Router,
         content_handle: ContentHandle,
     ) -> std::io::Result<Rc<RefCell<RequestContext>>> {
         let actor = Rc::new(RefCell::new(RequestContext::new(
             self.ctr_queue.clone(),
             self.efd,
-            verbose,
+            config,
+            addresses,
+            routes,
             content_handle,
         )));
         reactor.add_interest(self.efd, READ, actor.clone())?;
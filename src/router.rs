@@ -0,0 +1,359 @@
+use std::path::Path;
+use std::rc::Rc;
+
+use crate::http::{Request, Response};
+use crate::multipart::Form;
+use crate::upload;
+
+/// Request body as handed to a handler.
+pub enum Body<'a> {
+    Empty,
+    /// Body streamed to the upload directory. The file is removed once the
+    /// handler returns, unless it was moved with [`Body::persist`].
+    File(&'a Path),
+    /// `multipart/form-data` body, with file parts in the upload directory,
+    /// removed likewise unless moved with
+    /// [`FilePart::persist`](crate::multipart::FilePart::persist).
+    Form(&'a Form),
+}
+
+impl Body<'_> {
+    /// Moves a [`Body::File`] to `to`, where it is kept.
+    ///
+    /// # Errors
+    ///
+    /// Fails if the body is not a file or it cannot be moved.
+    pub fn persist(&self, to: &Path) -> std::io::Result<()> {
+        match self {
+            Body::File(path) => upload::persist(path, to),
+            Body::Empty | Body::Form(_) => Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                "the body is not a file",
+            )),
+        }
+    }
+}
+
+/// Produces the response to a fully received request.
+pub trait Handler {
+    fn handle(&self, req: &Request, params: &Params<'_>, body: &Body<'_>) -> Response;
+}
+
+impl<F> Handler for F
+where
+    F: Fn(&Request, &Params<'_>, &Body<'_>) -> Response,
+{
+    fn handle(&self, req: &Request, params: &Params<'_>, body: &Body<'_>) -> Response {
+        self(req, params, body)
+    }
+}
+
+/// Path segments captured by `:name` and `*name` patterns.
+#[derive(Default)]
+pub struct Params<'a> {
+    pairs: Vec<(&'a str, &'a str)>,
+}
+
+impl<'a> Params<'a> {
+    #[must_use]
+    pub fn get(&self, name: &str) -> Option<&'a str> {
+        self.pairs.iter().find(|(n, _)| *n == name).map(|(_, v)| *v)
+    }
+}
+
+enum Segment {
+    Literal(String),
+    Param(String),
+    /// Matches the rest of the path; only valid as the last segment.
+    Wildcard(String),
+}
+
+struct Route {
+    method: String,
+    segments: Vec<Segment>,
+    handler: Rc<dyn Handler>,
+}
+
+impl Route {
+    fn matches<'a>(&'a self, path: &'a str, params: &mut Params<'a>) -> bool {
+        params.pairs.clear();
+        // `None` once the last segment of the path is taken
+        let mut rest = Some(path.strip_prefix('/').unwrap_or(path));
+        for segment in &self.segments {
+            let Some(current) = rest else {
+                return false;
+            };
+            if let Segment::Wildcard(name) = segment {
+                params.pairs.push((name, current));
+                return true;
+            }
+            let (part, tail) = match current.split_once('/') {
+                Some((part, tail)) => (part, Some(tail)),
+                None => (current, None),
+            };
+            match segment {
+                Segment::Literal(literal) if literal == part => {}
+                Segment::Param(name) if !part.is_empty() => params.pairs.push((name, part)),
+                _ => return false,
+            }
+            rest = tail;
+        }
+        rest.is_none()
+    }
+}
+
+/// Why no handler was found for a request.
+pub enum RouteError {
+    NotFound,
+    /// The path is known, but not for this method; holds the allowed ones.
+    MethodNotAllowed(Vec<String>),
+}
+
+/// Maps a method and a path pattern to a handler. Patterns are split at
+/// `/`; a `:name` segment captures one path segment and a trailing `*name`
+/// (or a bare `*`) captures the remainder of the path, which may be empty.
+/// A trailing slash counts, so `/dir/` does not match `/dir`, nor `/dir`
+/// match `/dir/`.
+#[derive(Default)]
+pub struct Router {
+    routes: Vec<Route>,
+}
+
+impl Router {
+    #[must_use]
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Registers `handler` for `method` requests whose path matches `pattern`.
+    ///
+    /// # Errors
+    ///
+    /// Fails if a wildcard is not the last segment of `pattern`.
+    pub fn add(
+        &mut self,
+        method: &str,
+        pattern: &str,
+        handler: impl Handler + 'static,
+    ) -> Result<(), String> {
+        let pattern = pattern.strip_prefix('/').unwrap_or(pattern);
+        let segments: Vec<Segment> = pattern
+            .split('/')
+            .map(|s| {
+                if let Some(name) = s.strip_prefix(':') {
+                    Segment::Param(name.to_owned())
+                } else if let Some(name) = s.strip_prefix('*') {
+                    Segment::Wildcard(if name.is_empty() { "*" } else { name }.to_owned())
+                } else {
+                    Segment::Literal(s.to_owned())
+                }
+            })
+            .collect();
+        let wildcard_inside = segments
+            .iter()
+            .rev()
+            .skip(1)
+            .any(|s| matches!(s, Segment::Wildcard(_)));
+        if wildcard_inside {
+            return Err(format!("wildcard must be the last segment of /{pattern}"));
+        }
+        self.routes.push(Route {
+            method: method.to_owned(),
+            segments,
+            handler: Rc::new(handler),
+        });
+        Ok(())
+    }
+
+    /// Registers a `GET` route, see [`Router::add`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if a wildcard is not the last segment of `pattern`.
+    pub fn get(&mut self, pattern: &str, handler: impl Handler + 'static) -> Result<(), String> {
+        self.add("GET", pattern, handler)
+    }
+
+    /// Registers a `POST` route, see [`Router::add`].
+    ///
+    /// # Errors
+    ///
+    /// Fails if a wildcard is not the last segment of `pattern`.
+    pub fn post(&mut self, pattern: &str, handler: impl Handler + 'static) -> Result<(), String> {
+        self.add("POST", pattern, handler)
+    }
+
+    /// Finds the handler for `req`. `HEAD` requests fall back to `GET` routes.
+    ///
+    /// # Errors
+    ///
+    /// Returns [`RouteError`] when no route matches the path or the method.
+    pub fn route<'a>(
+        &'a self,
+        req: &'a Request,
+    ) -> Result<(Rc<dyn Handler>, Params<'a>), RouteError> {
+        let path = req.path();
+        let method = req.method();
+        let mut params = Params::default();
+        let mut allowed = Vec::new();
+        for route in &self.routes {
+            if !route.matches(path, &mut params) {
+                continue;
+            }
+            if route.method == method || (method == "HEAD" && route.method == "GET") {
+                return Ok((route.handler.clone(), params));
+            }
+            if !allowed.contains(&route.method) {
+                allowed.push(route.method.clone());
+            }
+        }
+        if allowed.is_empty() {
+            Err(RouteError::NotFound)
+        } else {
+            if allowed.iter().any(|m| m == "GET") {
+                allowed.push("HEAD".to_owned());
+            }
+            Err(RouteError::MethodNotAllowed(allowed))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::http::Parser;
+
+    fn request(method: &str, path: &str) -> Request {
+        let head = format!("{method} {path} HTTP/1.1\r\nHost: x\r\n\r\n");
+        let mut req = Request::default();
+        Parser::default()
+            .parse(head.as_bytes(), &mut req)
+            .unwrap()
+            .unwrap();
+        req
+    }
+
+    /// Answers with the status `status`, to tell which route matched.
+    fn handler(status: u16) -> impl Handler {
+        move |_: &Request, _: &Params<'_>, _: &Body<'_>| Response::new(status)
+    }
+
+    fn router() -> Router {
+        let mut router = Router::new();
+        router.get("/", handler(200)).unwrap();
+        router.get("/users/:id", handler(201)).unwrap();
+        router.post("/users/:id", handler(202)).unwrap();
+        router.get("/users/:id/posts/:post", handler(203)).unwrap();
+        router.get("/files/*path", handler(204)).unwrap();
+        router.add("PUT", "/dir/", handler(205)).unwrap();
+        router
+    }
+
+    /// The status of the matched handler and the captured parameters.
+    fn route(router: &Router, method: &str, path: &str, names: &[&str]) -> (u16, Vec<String>) {
+        let req = request(method, path);
+        let Ok((handler, params)) = router.route(&req) else {
+            panic!("{method} {path} did not match");
+        };
+        let status = handler.handle(&req, &params, &Body::Empty).status();
+        let values = names
+            .iter()
+            .map(|name| params.get(name).unwrap_or("-").to_owned())
+            .collect();
+        (status, values)
+    }
+
+    #[test]
+    fn params_are_captured() {
+        let router = router();
+        assert_eq!(route(&router, "GET", "/", &[]), (200, vec![]));
+        assert_eq!(
+            route(&router, "GET", "/users/42", &["id"]),
+            (201, vec!["42".to_owned()])
+        );
+        assert_eq!(
+            route(&router, "GET", "/users/7/posts/x", &["id", "post"]),
+            (203, vec!["7".to_owned(), "x".to_owned()])
+        );
+        for path in ["/users", "/users/", "/users//posts/x", "/users/7/posts"] {
+            assert!(
+                matches!(
+                    router.route(&request("GET", path)),
+                    Err(RouteError::NotFound)
+                ),
+                "{path}"
+            );
+        }
+    }
+
+    #[test]
+    fn wildcard_takes_the_rest() {
+        let router = router();
+        for (path, rest) in [
+            ("/files/a.txt", "a.txt"),
+            ("/files/a/b/c", "a/b/c"),
+            ("/files/a/", "a/"),
+            ("/files/", ""),
+        ] {
+            assert_eq!(
+                route(&router, "GET", path, &["path"]),
+                (204, vec![rest.to_owned()]),
+                "{path}"
+            );
+        }
+        assert!(matches!(
+            router.route(&request("GET", "/files")),
+            Err(RouteError::NotFound)
+        ));
+        let mut router = Router::new();
+        router.get("/*", handler(200)).unwrap();
+        assert_eq!(
+            route(&router, "GET", "/x/y", &["*"]),
+            (200, vec!["x/y".to_owned()])
+        );
+        assert!(router.get("/*path/x", handler(200)).is_err());
+    }
+
+    #[test]
+    fn trailing_slash_is_significant() {
+        let router = router();
+        assert_eq!(route(&router, "PUT", "/dir/", &[]), (205, vec![]));
+        assert!(matches!(
+            router.route(&request("PUT", "/dir")),
+            Err(RouteError::NotFound)
+        ));
+        assert!(matches!(
+            router.route(&request("GET", "/users/42/")),
+            Err(RouteError::NotFound)
+        ));
+    }
+
+    #[test]
+    fn head_falls_back_to_get() {
+        let router = router();
+        assert_eq!(
+            route(&router, "HEAD", "/users/1", &["id"]),
+            (201, vec!["1".to_owned()])
+        );
+        assert_eq!(
+            route(&router, "POST", "/users/1", &["id"]),
+            (202, vec!["1".to_owned()])
+        );
+    }
+
+    #[test]
+    fn other_methods_are_not_allowed() {
+        let router = router();
+        let Err(RouteError::MethodNotAllowed(allowed)) =
+            router.route(&request("DELETE", "/users/1"))
+        else {
+            panic!("expected 405");
+        };
+        assert_eq!(allowed, ["GET", "POST", "HEAD"]);
+        let Err(RouteError::MethodNotAllowed(allowed)) = router.route(&request("GET", "/dir/"))
+        else {
+            panic!("expected 405");
+        };
+        assert_eq!(allowed, ["PUT"]);
+    }
+}
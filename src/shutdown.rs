@@ -0,0 +1,155 @@
+use std::os::fd::RawFd;
+use std::time::Duration;
+
+use crate::reactor::{EventReceiver, InterestAction, InterestActions, State, Token};
+use crate::request_context::{Handle as ReqHandle, Message as ReqMessage};
+use crate::signal::SignalEvent;
+use crate::timer_queue::TimerOp;
+use crate::{log, syscall};
+
+/// Timer key of the drain deadline.
+const DEADLINE: u64 = 0;
+
+/// Stop flags shared by the reactors of all workers, one eventfd for each
+/// phase of a shutdown. Nobody reads them, so once triggered they stay
+/// readable and every reactor watching them sees it.
+pub struct Shutdown {
+    drain_fd: RawFd,
+    force_fd: RawFd,
+}
+
+impl Shutdown {
+    pub(crate) fn new() -> std::io::Result<Self> {
+        let drain_fd = syscall!(eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC))?;
+        let force_fd = match syscall!(eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC)) {
+            Ok(fd) => fd,
+            Err(e) => {
+                let _ = unsafe { libc::close(drain_fd) };
+                return Err(e);
+            }
+        };
+        Ok(Self { drain_fd, force_fd })
+    }
+
+    /// Stops at once.
+    pub(crate) fn force(&self) -> std::io::Result<()> {
+        trigger(self.force_fd)
+    }
+
+    #[inline]
+    pub(crate) fn drain_fd(&self) -> RawFd {
+        self.drain_fd
+    }
+
+    #[inline]
+    pub(crate) fn force_fd(&self) -> RawFd {
+        self.force_fd
+    }
+}
+
+impl Drop for Shutdown {
+    fn drop(&mut self) {
+        let _ = unsafe { libc::close(self.drain_fd) };
+        let _ = unsafe { libc::close(self.force_fd) };
+    }
+}
+
+pub(crate) fn trigger(fd: RawFd) -> std::io::Result<()> {
+    syscall!(eventfd_write(fd, 1))?;
+    Ok(())
+}
+
+/// Whether the phase behind `fd` has been triggered, leaving it readable.
+pub(crate) fn triggered(fd: RawFd) -> bool {
+    let mut pollfd = libc::pollfd {
+        fd,
+        events: libc::POLLIN,
+        revents: 0,
+    };
+    syscall!(poll(&raw mut pollfd, 1, 0)).is_ok_and(|ready| ready > 0)
+}
+
+/// Watches both fds of a [`Shutdown`], which stay owned by it. Draining
+/// stops the request listeners and tells the request context to finish
+/// what is in flight, which stops the reactor once done; the reactor is
+/// stopped anyway when `timeout` runs out or on a forced shutdown.
+pub struct Listener {
+    verbose: bool,
+    force_fd: RawFd,
+    /// Registrations of the request listeners.
+    accepts: Vec<Token>,
+    req_handle: ReqHandle,
+    timeout: Duration,
+}
+
+impl Listener {
+    pub(crate) fn new(
+        verbose: bool,
+        shutdown: &Shutdown,
+        accepts: Vec<Token>,
+        req_handle: ReqHandle,
+        timeout: Duration,
+    ) -> Self {
+        Self {
+            verbose,
+            force_fd: shutdown.force_fd(),
+            accepts,
+            req_handle,
+            timeout,
+        }
+    }
+}
+
+impl EventReceiver for Listener {
+    fn on_ready(
+        &mut self,
+        ready_to: State,
+        fd: RawFd,
+        token: Token,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        debug_assert!(ready_to.read());
+        if fd == self.force_fd {
+            new_actions.add(InterestAction::Exit);
+            return Ok(());
+        }
+        // the drain fd stays readable, so it is not re-armed
+        if self.verbose {
+            log("draining connections");
+        }
+        for &accept in &self.accepts {
+            new_actions.add(InterestAction::Remove(accept));
+        }
+        new_actions.add(InterestAction::Timer(
+            token,
+            DEADLINE,
+            TimerOp::Once(self.timeout),
+        ));
+        self.req_handle.enqueue(ReqMessage::Drain)
+    }
+
+    fn on_timer(
+        &mut self,
+        _token: Token,
+        _key: u64,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if self.verbose {
+            log("drain timeout, closing the remaining connections");
+        }
+        new_actions.add(InterestAction::Exit);
+        Ok(())
+    }
+
+    fn on_signal(
+        &mut self,
+        event: &SignalEvent,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if let SignalEvent::Reload(config) = event {
+            self.verbose = config.verbose();
+            self.timeout = config.drain_timeout;
+        }
+        Ok(())
+    }
+}
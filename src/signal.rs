@@ -1,35 +1,333 @@
+use std::cell::RefCell;
 use std::mem::MaybeUninit;
 use std::os::fd::RawFd;
 use std::os::raw::c_void;
+use std::rc::Rc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
 
-use crate::reactor::State;
-use crate::syscall;
+use crate::config::{Config, Reloadable};
+use crate::reactor::{State, Token, READ};
+use crate::shutdown::{self, Shutdown};
+use crate::upgrade;
 use crate::EventReceiver;
 use crate::InterestAction;
 use crate::InterestActions;
+use crate::{log, log_error, reopen_log, set_log_level, syscall};
 
-pub struct Listener {
-    fd: RawFd,
+/// Signals that can be given an action.
+const SIGNALS: [(&str, libc::c_int); 6] = [
+    ("INT", libc::SIGINT),
+    ("TERM", libc::SIGTERM),
+    ("HUP", libc::SIGHUP),
+    ("QUIT", libc::SIGQUIT),
+    ("USR1", libc::SIGUSR1),
+    ("USR2", libc::SIGUSR2),
+];
+
+fn signal_name(signo: libc::c_int) -> &'static str {
+    SIGNALS
+        .iter()
+        .find(|(_, s)| *s == signo)
+        .map_or("?", |(name, _)| name)
 }
 
-impl Listener {
-    pub(crate) fn new() -> std::io::Result<Self> {
+/// Delivered to every receiver of every worker through
+/// [`EventReceiver::on_signal`](crate::reactor::EventReceiver::on_signal).
+#[derive(Clone, Debug)]
+pub enum SignalEvent {
+    /// The configuration was read again; it applies to new requests.
+    Reload(Arc<Config>),
+    /// Log what is going on.
+    DumpStats,
+}
+
+/// What a signal is turned into.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SignalAction {
+    /// Shut down gracefully, or at once if already doing so.
+    Drain,
+    /// Shut down at once.
+    Exit,
+    /// Read the configuration again and pass it on.
+    Reload,
+    /// Start the executable again with the listening sockets, then drain.
+    Upgrade,
+    DumpStats,
+    /// Open the log file again, after it was rotated.
+    ReopenLogs,
+    Ignore,
+}
+
+impl FromStr for SignalAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "drain" => Ok(SignalAction::Drain),
+            "exit" => Ok(SignalAction::Exit),
+            "reload" => Ok(SignalAction::Reload),
+            "upgrade" => Ok(SignalAction::Upgrade),
+            "stats" => Ok(SignalAction::DumpStats),
+            "reopen-logs" => Ok(SignalAction::ReopenLogs),
+            "ignore" => Ok(SignalAction::Ignore),
+            _ => Err(format!("unknown signal action {s}")),
+        }
+    }
+}
+
+/// One entry of [`Signals`], written `NAME=ACTION` as in `USR1=stats`.
+pub struct Binding(libc::c_int, SignalAction);
+
+impl FromStr for Binding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, action) = s
+            .split_once('=')
+            .ok_or_else(|| format!("expected NAME=ACTION, got {s}"))?;
+        let name = name.to_ascii_uppercase();
+        let name = name.strip_prefix("SIG").unwrap_or(&name);
+        let signo = SIGNALS
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, signo)| *signo)
+            .ok_or_else(|| format!("unsupported signal {name}"))?;
+        Ok(Binding(signo, action.parse()?))
+    }
+}
+
+/// The action of each handled signal.
+#[derive(Clone)]
+pub struct Signals {
+    actions: Vec<(libc::c_int, SignalAction)>,
+}
+
+impl Default for Signals {
+    fn default() -> Self {
+        Self {
+            actions: vec![
+                (libc::SIGINT, SignalAction::Drain),
+                (libc::SIGTERM, SignalAction::Drain),
+                (libc::SIGQUIT, SignalAction::Exit),
+                (libc::SIGHUP, SignalAction::Reload),
+                (libc::SIGUSR1, SignalAction::DumpStats),
+                (libc::SIGUSR2, SignalAction::Upgrade),
+            ],
+        }
+    }
+}
+
+impl Signals {
+    pub(crate) fn bind(&mut self, Binding(signo, action): Binding) {
+        match self.actions.iter_mut().find(|(s, _)| *s == signo) {
+            Some(entry) => entry.1 = action,
+            None => self.actions.push((signo, action)),
+        }
+    }
+
+    fn action(&self, signo: libc::c_int) -> SignalAction {
+        self.actions
+            .iter()
+            .find(|(s, _)| *s == signo)
+            .map_or(SignalAction::Ignore, |(_, action)| *action)
+    }
+
+    fn mask(&self) -> std::io::Result<libc::sigset_t> {
         let mut mask = MaybeUninit::<libc::sigset_t>::uninit();
         syscall!(sigemptyset(mask.as_mut_ptr()))?;
         let mut mask = unsafe { mask.assume_init() };
-        syscall!(sigaddset(&mut mask, libc::SIGINT))?;
+        for (signo, _) in &self.actions {
+            syscall!(sigaddset(&raw mut mask, *signo))?;
+        }
+        Ok(mask)
+    }
+
+    /// Blocks the handled signals for the calling thread and the threads it
+    /// spawns afterwards, so that they only ever reach the listeners.
+    pub(crate) fn block(&self) -> std::io::Result<()> {
+        let mask = self.mask()?;
         syscall!(sigprocmask(
             libc::SIG_BLOCK,
-            &mut mask,
+            &raw const mask,
             std::ptr::null_mut()
         ))?;
-        let fd = syscall!(signalfd(-1, &mask, 0))?;
+        Ok(())
+    }
+}
+
+struct Inbox {
+    efd: RawFd,
+    events: Mutex<Vec<SignalEvent>>,
+}
+
+/// Passes signal events on to every worker, whichever of them read the
+/// signal. Each worker has an inbox with an eventfd to wake its listener.
+pub struct Hub {
+    inboxes: Vec<Inbox>,
+}
+
+impl Hub {
+    pub(crate) fn new(workers: usize) -> std::io::Result<Self> {
+        let mut hub = Self {
+            inboxes: Vec::with_capacity(workers),
+        };
+        for _ in 0..workers {
+            let efd = syscall!(eventfd(0, libc::EFD_NONBLOCK | libc::EFD_CLOEXEC))?;
+            hub.inboxes.push(Inbox {
+                efd,
+                events: Mutex::new(Vec::new()),
+            });
+        }
+        Ok(hub)
+    }
+
+    fn send(&self, event: &SignalEvent) -> std::io::Result<()> {
+        for inbox in &self.inboxes {
+            inbox
+                .events
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner)
+                .push(event.clone());
+            syscall!(eventfd_write(inbox.efd, 1))?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Hub {
+    fn drop(&mut self) {
+        for inbox in &self.inboxes {
+            let _ = unsafe { libc::close(inbox.efd) };
+        }
+    }
+}
+
+/// Reads the signals in [`Signals`] and dispatches them. Shutdowns go
+/// through [`Shutdown`], and events through the [`Hub`] to the inboxes,
+/// from where each worker's listener hands them to its reactor. Every
+/// worker has its own listener, so that signals are still seen while any
+/// of them runs; whichever reads a signal first handles it.
+pub struct Listener {
+    verbose: bool,
+    fd: RawFd,
+    signals: Signals,
+    drain_fd: RawFd,
+    force_fd: RawFd,
+    hub: Arc<Hub>,
+    /// Index of this worker's inbox in the hub.
+    worker: usize,
+    config: Arc<Reloadable>,
+    /// Listening sockets of all workers, handed over on upgrades.
+    sockets: Vec<RawFd>,
+}
+
+impl Listener {
+    /// Expects the signals to be [`Signals::block`]ed.
+    pub(crate) fn new(
+        verbose: bool,
+        signals: Signals,
+        shutdown: &Shutdown,
+        hub: Arc<Hub>,
+        worker: usize,
+        config: Arc<Reloadable>,
+        sockets: Vec<RawFd>,
+    ) -> std::io::Result<Self> {
+        let mask = signals.mask()?;
+        let fd = syscall!(signalfd(
+            -1,
+            &raw const mask,
+            libc::SFD_NONBLOCK | libc::SFD_CLOEXEC
+        ))?;
 
-        Ok(Self { fd })
+        Ok(Self {
+            verbose,
+            fd,
+            signals,
+            drain_fd: shutdown.drain_fd(),
+            force_fd: shutdown.force_fd(),
+            hub,
+            worker,
+            config,
+            sockets,
+        })
     }
 
     #[inline]
     pub(crate) fn raw_fd(&self) -> RawFd { self.fd }
+
+    #[inline]
+    pub(crate) fn inbox_fd(&self) -> RawFd {
+        self.hub.inboxes[self.worker].efd
+    }
+
+    fn dispatch(&self, new_actions: &mut InterestActions) -> std::io::Result<()> {
+        let mut siginfo = MaybeUninit::<libc::signalfd_siginfo>::uninit();
+        let siginfo_size = size_of::<libc::signalfd_siginfo>();
+        match syscall!(read(
+            self.fd,
+            siginfo.as_mut_ptr().cast::<c_void>(),
+            siginfo_size
+        )) {
+            Ok(_) => {}
+            // another worker's listener took it
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => return Ok(()),
+            Err(e) => return Err(e),
+        }
+        #[allow(clippy::cast_possible_wrap)]
+        let signo = unsafe { siginfo.assume_init() }.ssi_signo as libc::c_int;
+        let action = self.signals.action(signo);
+        if self.verbose {
+            log(&format!("got SIG{}: {action:?}", signal_name(signo)));
+        }
+
+        match action {
+            SignalAction::Drain if !shutdown::triggered(self.drain_fd) => {
+                shutdown::trigger(self.drain_fd)
+            }
+            SignalAction::Drain | SignalAction::Exit => shutdown::trigger(self.force_fd),
+            SignalAction::Reload => match self.config.load() {
+                Ok(config) => {
+                    set_log_level(config.log_level);
+                    self.hub.send(&SignalEvent::Reload(Arc::new(config)))
+                }
+                // the current configuration stays
+                Err(e) => {
+                    log_error(&format!("could not reload the configuration: {e}"));
+                    Ok(())
+                }
+            },
+            SignalAction::Upgrade if shutdown::triggered(self.drain_fd) => {
+                log_error("not upgrading while shutting down");
+                Ok(())
+            }
+            // the sockets stay open in the new process, and this one
+            // closes them and finishes what it has once that one serves
+            SignalAction::Upgrade => match upgrade::spawn(&self.sockets, self.drain_fd) {
+                Ok(watch) => {
+                    log(&format!(
+                        "started process {}, waiting for it to be ready",
+                        watch.pid()
+                    ));
+                    let fd = watch.raw_fd();
+                    new_actions.add(InterestAction::Add(fd, READ, Rc::new(RefCell::new(watch))));
+                    Ok(())
+                }
+                Err(e) => {
+                    log_error(&format!("could not upgrade: {e}"));
+                    Ok(())
+                }
+            },
+            SignalAction::DumpStats => self.hub.send(&SignalEvent::DumpStats),
+            SignalAction::ReopenLogs => {
+                if let Err(e) = reopen_log() {
+                    log_error(&format!("could not reopen the log: {e}"));
+                }
+                Ok(())
+            }
+            SignalAction::Ignore => Ok(()),
+        }
+    }
 }
 
 impl Drop for Listener {
@@ -43,18 +341,37 @@ impl EventReceiver for Listener {
         &mut self,
         ready_to: State,
         fd: RawFd,
+        token: Token,
         new_actions: &mut InterestActions,
     ) -> std::io::Result<()> {
         debug_assert!(ready_to.read());
-        let mut siginfo = MaybeUninit::<libc::signalfd_siginfo>::uninit();
-        let siginfo_size = size_of::<libc::signalfd_siginfo>();
-        syscall!(read(
-            fd,
-            siginfo.as_mut_ptr().cast::<c_void>(),
-            siginfo_size
-        ))?;
+        new_actions.add(InterestAction::Modify(token, READ));
+        if fd == self.fd {
+            return self.dispatch(new_actions);
+        }
 
-        new_actions.add(InterestAction::Exit);
+        let mut value = MaybeUninit::<u64>::uninit();
+        syscall!(eventfd_read(fd, value.as_mut_ptr()))?;
+        let events = std::mem::take(
+            &mut *self.hub.inboxes[self.worker]
+                .events
+                .lock()
+                .unwrap_or_else(std::sync::PoisonError::into_inner),
+        );
+        for event in events {
+            new_actions.add(InterestAction::Signal(event));
+        }
+        Ok(())
+    }
+
+    fn on_signal(
+        &mut self,
+        event: &SignalEvent,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if let SignalEvent::Reload(config) = event {
+            self.verbose = config.verbose();
+        }
         Ok(())
     }
 }
@@ -3,7 +3,7 @@ use std::os::fd::RawFd;
 use std::os::raw::c_void;
 
 use crate::syscall;
-use crate::reactor::{State, EventReceiver, InterestAction, InterestActions, READ};
+use crate::reactor::{State, EventReceiver, InterestAction, InterestActions, Token, READ};
 
 pub struct Listener {
     fd: RawFd,
@@ -11,7 +11,7 @@ pub struct Listener {
 
 impl Listener {
     pub(crate) fn new() -> std::io::Result<Self> {
-        let fd = syscall!(timerfd_create(libc::CLOCK_MONOTONIC, 0))?;
+        let fd = syscall!(timerfd_create(libc::CLOCK_MONOTONIC, libc::TFD_CLOEXEC))?;
         let timer_spec = libc::itimerspec {
             it_value: libc::timespec {
                 tv_sec: 1,
@@ -22,7 +22,12 @@ impl Listener {
                 tv_nsec: 0,
             },
         };
-        syscall!(timerfd_settime(fd, 0, &timer_spec, std::ptr::null_mut()))?;
+        syscall!(timerfd_settime(
+            fd,
+            0,
+            &raw const timer_spec,
+            std::ptr::null_mut()
+        ))?;
         Ok(Self { fd })
     }
 
@@ -41,6 +46,7 @@ impl EventReceiver for Listener {
         &mut self,
         ready_to: State,
         fd: RawFd,
+        token: Token,
         new_actions: &mut InterestActions,
     ) -> std::io::Result<()> {
         debug_assert!(ready_to.read());
@@ -53,7 +59,7 @@ impl EventReceiver for Listener {
         ))?;
 
         new_actions.add(InterestAction::PrintStats);
-        new_actions.add(InterestAction::Modify(self.fd, READ));
+        new_actions.add(InterestAction::Modify(token, READ));
         Ok(())
     }
 }
@@ -0,0 +1,337 @@
+use std::time::{Duration, Instant};
+
+use crate::reactor::Token;
+
+/// Shortest period of a periodic timer, so that one always lies ahead.
+const MIN_PERIOD: Duration = Duration::from_millis(1);
+
+/// What to do with the timer `key` of a receiver.
+#[derive(Clone, Copy)]
+pub enum TimerOp {
+    /// Fire once after the given delay.
+    Once(Duration),
+    /// Fire after the given period and then again every period, which is
+    /// at least a millisecond.
+    Every(Duration),
+    Cancel,
+}
+
+struct Entry {
+    at: Instant,
+    token: Token,
+    key: u64,
+    period: Option<Duration>,
+}
+
+/// Timers of one registration slot: their keys and positions in the heap.
+#[derive(Default)]
+struct SlotTimers {
+    generation: u32,
+    timers: Vec<(u64, usize)>,
+}
+
+/// Deadlines of the receivers registered with a [`Reactor`](crate::reactor::Reactor).
+/// Timers are identified by the registration's token and a key of the
+/// receiver's choosing; scheduling an existing timer again moves it in
+/// place, so the heap only ever holds live timers and stops growing once
+/// the number of connections does.
+#[derive(Default)]
+pub(crate) struct TimerQueue {
+    heap: Vec<Entry>,
+    /// Indexed like the reactor's registration slots.
+    slots: Vec<SlotTimers>,
+}
+
+impl TimerQueue {
+    pub fn apply(&mut self, token: Token, key: u64, op: TimerOp) {
+        let (after, period) = match op {
+            TimerOp::Once(after) => (after, None),
+            TimerOp::Every(period) => {
+                let period = period.max(MIN_PERIOD);
+                (period, Some(period))
+            }
+            TimerOp::Cancel => {
+                if let Some(pos) = self.position(token, key) {
+                    self.remove_at(pos);
+                }
+                return;
+            }
+        };
+        let at = Instant::now() + after;
+        if let Some(pos) = self.position(token, key) {
+            self.heap[pos].at = at;
+            self.heap[pos].period = period;
+            self.sift(pos);
+            return;
+        }
+        self.claim_slot(token);
+        let pos = self.heap.len();
+        self.heap.push(Entry {
+            at,
+            token,
+            key,
+            period,
+        });
+        self.slots[token.index()].timers.push((key, pos));
+        self.sift_up(pos);
+    }
+
+    /// Drops every timer of `token`, which is no longer registered.
+    pub fn remove(&mut self, token: Token) {
+        while let Some(&(_, pos)) = self
+            .slots
+            .get(token.index())
+            .filter(|slot| slot.generation == token.generation())
+            .and_then(|slot| slot.timers.first())
+        {
+            self.remove_at(pos);
+        }
+    }
+
+    /// Milliseconds until the nearest deadline as `epoll_wait` expects
+    /// them, rounded up so that the deadline has passed on wake-up, or -1
+    /// if there is none.
+    pub fn timeout(&self, now: Instant) -> i32 {
+        self.heap.first().map_or(-1, |entry| {
+            let wait = entry.at.saturating_duration_since(now);
+            let ms = wait.as_nanos().div_ceil(1_000_000);
+            i32::try_from(ms).unwrap_or(i32::MAX)
+        })
+    }
+
+    /// Pops the next timer due at `now`, rescheduling it if periodic. A
+    /// periodic timer that fell behind fires once and goes on a period
+    /// from `now`, rather than catching up on the periods it missed.
+    pub fn pop_due(&mut self, now: Instant) -> Option<(Token, u64)> {
+        let entry = self.heap.first_mut().filter(|entry| entry.at <= now)?;
+        let fired = (entry.token, entry.key);
+        match entry.period {
+            Some(period) => {
+                entry.at = (entry.at + period).max(now + period);
+                self.sift_down(0);
+            }
+            None => self.remove_at(0),
+        }
+        Some(fired)
+    }
+
+    /// Makes the slot of `token` ready to take its timers.
+    fn claim_slot(&mut self, token: Token) {
+        let index = token.index();
+        if index >= self.slots.len() {
+            self.slots.resize_with(index + 1, SlotTimers::default);
+        }
+        if self.slots[index].generation != token.generation() {
+            // timers of an earlier registration are removed along with it,
+            // and any left over go now
+            while let Some(&(_, pos)) = self.slots[index].timers.first() {
+                self.remove_at(pos);
+            }
+            self.slots[index].generation = token.generation();
+        }
+    }
+
+    fn position(&self, token: Token, key: u64) -> Option<usize> {
+        let slot = self.slots.get(token.index())?;
+        if slot.generation != token.generation() {
+            return None;
+        }
+        slot.timers
+            .iter()
+            .find(|(k, _)| *k == key)
+            .map(|(_, pos)| *pos)
+    }
+
+    fn set_position(&mut self, pos: usize) {
+        let Entry { token, key, .. } = self.heap[pos];
+        if let Some(timer) = self.slots[token.index()]
+            .timers
+            .iter_mut()
+            .find(|(k, _)| *k == key)
+        {
+            timer.1 = pos;
+        }
+    }
+
+    fn remove_at(&mut self, pos: usize) {
+        let entry = self.heap.swap_remove(pos);
+        let slot = &mut self.slots[entry.token.index()];
+        slot.timers.retain(|(k, _)| *k != entry.key);
+        if pos < self.heap.len() {
+            self.set_position(pos);
+            self.sift(pos);
+        }
+    }
+
+    fn sift(&mut self, pos: usize) {
+        let pos = self.sift_up(pos);
+        self.sift_down(pos);
+    }
+
+    fn sift_up(&mut self, mut pos: usize) -> usize {
+        while pos > 0 {
+            let parent = (pos - 1) / 2;
+            if self.heap[parent].at <= self.heap[pos].at {
+                break;
+            }
+            self.swap(pos, parent);
+            pos = parent;
+        }
+        pos
+    }
+
+    fn sift_down(&mut self, mut pos: usize) {
+        loop {
+            let mut next = pos;
+            for child in [2 * pos + 1, 2 * pos + 2] {
+                if child < self.heap.len() && self.heap[child].at < self.heap[next].at {
+                    next = child;
+                }
+            }
+            if next == pos {
+                break;
+            }
+            self.swap(pos, next);
+            pos = next;
+        }
+    }
+
+    fn swap(&mut self, a: usize, b: usize) {
+        self.heap.swap(a, b);
+        self.set_position(a);
+        self.set_position(b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn token(index: u32, generation: u32) -> Token {
+        Token::from_u64(u64::from(generation) << 32 | u64::from(index))
+    }
+
+    /// Checks the heap order and that every slot knows where its timers are.
+    fn check(queue: &TimerQueue) {
+        for (pos, entry) in queue.heap.iter().enumerate().skip(1) {
+            assert!(
+                queue.heap[(pos - 1) / 2].at <= entry.at,
+                "heap order at {pos}"
+            );
+        }
+        for (pos, entry) in queue.heap.iter().enumerate() {
+            assert_eq!(queue.position(entry.token, entry.key), Some(pos));
+        }
+        let indexed: usize = queue.slots.iter().map(|slot| slot.timers.len()).sum();
+        assert_eq!(indexed, queue.heap.len());
+    }
+
+    fn drain(queue: &mut TimerQueue, now: Instant) -> Vec<(Token, u64)> {
+        let mut fired = Vec::new();
+        while let Some(timer) = queue.pop_due(now) {
+            check(queue);
+            fired.push(timer);
+        }
+        fired
+    }
+
+    fn secs(n: u64) -> TimerOp {
+        TimerOp::Once(Duration::from_secs(n))
+    }
+
+    #[test]
+    fn fires_in_deadline_order() {
+        let mut queue = TimerQueue::default();
+        let start = Instant::now();
+        for (index, delay) in [5, 1, 4, 2, 3, 6].into_iter().enumerate() {
+            queue.apply(token(u32::try_from(index).unwrap(), 0), 0, secs(delay));
+            check(&queue);
+        }
+        assert!(queue.pop_due(start).is_none());
+        let order: Vec<usize> = drain(&mut queue, start + Duration::from_secs(100))
+            .into_iter()
+            .map(|(token, _)| token.index())
+            .collect();
+        assert_eq!(order, [1, 3, 4, 2, 0, 5]);
+        assert_eq!(queue.timeout(start), -1);
+    }
+
+    #[test]
+    fn rescheduling_moves_a_timer_in_place() {
+        let mut queue = TimerQueue::default();
+        let start = Instant::now();
+        for key in 0..4 {
+            queue.apply(token(0, 0), key, secs(10 + key));
+        }
+        queue.apply(token(3, 0), 0, secs(1));
+        // the earliest becomes the latest, the latest the earliest
+        queue.apply(token(0, 0), 0, secs(20));
+        queue.apply(token(0, 0), 3, secs(2));
+        check(&queue);
+        assert_eq!(queue.heap.len(), 5);
+        let fired = drain(&mut queue, start + Duration::from_secs(100));
+        let keys: Vec<u64> = fired
+            .iter()
+            .filter(|(t, _)| *t == token(0, 0))
+            .map(|(_, key)| *key)
+            .collect();
+        assert_eq!(keys, [3, 1, 2, 0]);
+    }
+
+    #[test]
+    fn cancel_and_remove() {
+        let mut queue = TimerQueue::default();
+        let start = Instant::now();
+        for index in 0..3 {
+            for key in 0..3 {
+                queue.apply(token(index, 1), key, secs(u64::from(index) * 3 + key));
+            }
+        }
+        queue.apply(token(1, 1), 1, TimerOp::Cancel);
+        check(&queue);
+        // a stale token touches nothing
+        queue.apply(token(2, 0), 0, TimerOp::Cancel);
+        queue.remove(token(0, 0));
+        check(&queue);
+        assert_eq!(queue.heap.len(), 8);
+        queue.remove(token(0, 1));
+        check(&queue);
+        let fired = drain(&mut queue, start + Duration::from_secs(100));
+        assert_eq!(
+            fired,
+            [
+                (token(1, 1), 0),
+                (token(1, 1), 2),
+                (token(2, 1), 0),
+                (token(2, 1), 1),
+                (token(2, 1), 2),
+            ]
+        );
+    }
+
+    #[test]
+    fn a_new_generation_starts_afresh() {
+        let mut queue = TimerQueue::default();
+        let start = Instant::now();
+        queue.apply(token(0, 1), 0, secs(1));
+        queue.apply(token(0, 1), 1, secs(2));
+        queue.apply(token(1, 1), 0, secs(3));
+        queue.apply(token(0, 2), 1, secs(4));
+        check(&queue);
+        assert_eq!(queue.position(token(0, 1), 0), None);
+        let fired = drain(&mut queue, start + Duration::from_secs(100));
+        assert_eq!(fired, [(token(1, 1), 0), (token(0, 2), 1)]);
+    }
+
+    #[test]
+    fn periodic_timers_do_not_catch_up() {
+        let mut queue = TimerQueue::default();
+        let start = Instant::now();
+        queue.apply(token(0, 0), 0, TimerOp::Every(Duration::ZERO));
+        queue.apply(token(1, 0), 0, TimerOp::Every(Duration::from_millis(10)));
+        let now = start + Duration::from_secs(1);
+        assert_eq!(drain(&mut queue, now).len(), 2);
+        assert!(queue.pop_due(now).is_none());
+        assert_eq!(drain(&mut queue, now + Duration::from_millis(10)).len(), 2);
+    }
+}
@@ -0,0 +1,213 @@
+use std::mem::MaybeUninit;
+use std::os::fd::RawFd;
+use std::os::raw::c_void;
+use std::os::unix::process::CommandExt;
+use std::process::Command;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use crate::reactor::{
+    EventReceiver, InterestAction, InterestActions, State, Token, UnregisterReason, READ,
+};
+use crate::shutdown;
+use crate::{log, log_error, syscall};
+
+/// Lists the listening sockets a process hands over to the one replacing
+/// it, which finds them open under the same numbers.
+const FDS_VAR: &str = "UPGRADE_LISTEN_FDS";
+
+/// The write end of the pipe on which the new process tells the old one
+/// that it is serving.
+const READY_VAR: &str = "UPGRADE_READY_FD";
+
+/// Set while a new process is on its way, so that only one is started.
+static PENDING: AtomicBool = AtomicBool::new(false);
+
+/// Listening sockets inherited from the process this one replaces, if it
+/// was started by [`spawn`].
+pub(crate) fn inherited() -> std::io::Result<Option<Vec<RawFd>>> {
+    let Ok(list) = std::env::var(FDS_VAR) else {
+        return Ok(None);
+    };
+    let mut fds = Vec::new();
+    for fd in list.split(',') {
+        let fd: RawFd = fd.parse().map_err(|_| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidInput,
+                format!("invalid fd {fd} in {FDS_VAR}"),
+            )
+        })?;
+        // not to be leaked to anything started later
+        let flags = syscall!(fcntl(fd, libc::F_GETFD))?;
+        syscall!(fcntl(fd, libc::F_SETFD, flags | libc::FD_CLOEXEC))?;
+        fds.push(fd);
+    }
+    Ok(Some(fds))
+}
+
+/// Tells the process this one replaces, if it was started by [`spawn`],
+/// that it is serving and the old one may drain.
+pub(crate) fn ready() -> std::io::Result<()> {
+    let Ok(fd) = std::env::var(READY_VAR) else {
+        return Ok(());
+    };
+    let fd: RawFd = fd.parse().map_err(|_| {
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidInput,
+            format!("invalid fd {fd} in {READY_VAR}"),
+        )
+    })?;
+    let result = syscall!(write(fd, [1u8].as_ptr().cast::<c_void>(), 1));
+    let _ = unsafe { libc::close(fd) };
+    result.map(drop)
+}
+
+/// Starts the current executable again with the same arguments and hands
+/// it the listening sockets `fds`. The returned [`Watch`] learns when the
+/// new process is ready, which is when this one should drain.
+pub(crate) fn spawn(fds: &[RawFd], drain_fd: RawFd) -> std::io::Result<Watch> {
+    if PENDING.swap(true, Ordering::AcqRel) {
+        return Err(std::io::Error::other("an upgrade is already under way"));
+    }
+    let result = start(fds);
+    if result.is_err() {
+        PENDING.store(false, Ordering::Release);
+    }
+    let (pid, fd) = result?;
+    Ok(Watch {
+        pid,
+        fd,
+        drain_fd,
+        ready: false,
+    })
+}
+
+/// Starts the new process and returns its id along with the read end of
+/// the pipe it reports on.
+fn start(fds: &[RawFd]) -> std::io::Result<(u32, RawFd)> {
+    let mut pipe = [0; 2];
+    syscall!(pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC))?;
+    let [read_fd, write_fd] = pipe;
+    let result = command(fds, write_fd).and_then(|mut command| command.spawn());
+    // the new process has its own copy, if it started
+    let _ = unsafe { libc::close(write_fd) };
+    match result {
+        Ok(child) => Ok((child.id(), read_fd)),
+        Err(e) => {
+            let _ = unsafe { libc::close(read_fd) };
+            Err(e)
+        }
+    }
+}
+
+fn command(fds: &[RawFd], ready_fd: RawFd) -> std::io::Result<Command> {
+    let list = fds
+        .iter()
+        .map(ToString::to_string)
+        .collect::<Vec<_>>()
+        .join(",");
+    let mut fds = fds.to_vec();
+    fds.push(ready_fd);
+    // by the name it was started with, which finds a binary replaced on
+    // disk where the link to the running one would not
+    let mut args = std::env::args_os();
+    let program = match args.next() {
+        Some(program) => program,
+        None => std::env::current_exe()?.into_os_string(),
+    };
+    let mut command = Command::new(program);
+    command
+        .args(args)
+        .env(FDS_VAR, list)
+        .env(READY_VAR, ready_fd.to_string());
+    // runs in the child between fork and exec, where nothing but
+    // async-signal-safe calls such as fcntl may be made
+    unsafe {
+        command.pre_exec(move || {
+            for &fd in &fds {
+                let flags = libc::fcntl(fd, libc::F_GETFD);
+                if flags == -1 || libc::fcntl(fd, libc::F_SETFD, flags & !libc::FD_CLOEXEC) == -1 {
+                    return Err(std::io::Error::last_os_error());
+                }
+            }
+            Ok(())
+        });
+    }
+    Ok(command)
+}
+
+/// Waits for the process started by [`spawn`] to report that it is ready,
+/// and drains this one once it has. If it exits first, this one goes on
+/// serving and may be upgraded again.
+pub struct Watch {
+    pid: u32,
+    fd: RawFd,
+    drain_fd: RawFd,
+    ready: bool,
+}
+
+impl Watch {
+    #[inline]
+    pub(crate) fn pid(&self) -> u32 {
+        self.pid
+    }
+
+    #[inline]
+    pub(crate) fn raw_fd(&self) -> RawFd {
+        self.fd
+    }
+}
+
+impl Drop for Watch {
+    fn drop(&mut self) {
+        let _ = unsafe { libc::close(self.fd) };
+    }
+}
+
+impl EventReceiver for Watch {
+    fn on_ready(
+        &mut self,
+        ready_to: State,
+        fd: RawFd,
+        token: Token,
+        new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        debug_assert!(ready_to.read());
+        let mut byte = MaybeUninit::<u8>::uninit();
+        let read = match syscall!(read(fd, byte.as_mut_ptr().cast::<c_void>(), 1)) {
+            Ok(read) => read,
+            Err(e) if e.kind() == std::io::ErrorKind::WouldBlock => {
+                new_actions.add(InterestAction::Modify(token, READ));
+                return Ok(());
+            }
+            Err(e) => return Err(e),
+        };
+        new_actions.add(InterestAction::Remove(token));
+        if read == 0 {
+            return Ok(());
+        }
+        self.ready = true;
+        log(&format!("process {} is ready, draining this one", self.pid));
+        shutdown::trigger(self.drain_fd)
+    }
+
+    fn on_unregister(
+        &mut self,
+        _fd: RawFd,
+        _token: Token,
+        reason: UnregisterReason,
+        _new_actions: &mut InterestActions,
+    ) -> std::io::Result<()> {
+        if self.ready || matches!(reason, UnregisterReason::Shutdown) {
+            return Ok(());
+        }
+        // it closed the pipe by exiting, so it is reaped right away
+        #[allow(clippy::cast_possible_wrap)]
+        let _ = unsafe { libc::waitpid(self.pid as libc::pid_t, std::ptr::null_mut(), 0) };
+        PENDING.store(false, Ordering::Release);
+        log_error(&format!(
+            "process {} exited before it was ready, going on",
+            self.pid
+        ));
+        Ok(())
+    }
+}
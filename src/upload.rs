@@ -0,0 +1,66 @@
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Request body being streamed to disk. Data goes to a hidden `.part` file
+/// that is renamed once the body is complete, so the upload directory only
+/// ever shows finished uploads. An unfinished upload is removed on drop.
+pub struct Upload {
+    file: File,
+    temp: PathBuf,
+    path: PathBuf,
+    finished: bool,
+}
+
+impl Upload {
+    pub(crate) fn create(dir: &Path) -> std::io::Result<Self> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        let name = format!("upload-{}-{id}", std::process::id());
+        let temp = dir.join(format!(".{name}.part"));
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp)?;
+        Ok(Self {
+            file,
+            temp,
+            path: dir.join(name),
+            finished: false,
+        })
+    }
+
+    pub(crate) fn write(&mut self, data: &[u8]) -> std::io::Result<()> {
+        self.file.write_all(data)
+    }
+
+    /// Moves the complete upload to its final name and returns it.
+    pub(crate) fn finish(mut self) -> std::io::Result<PathBuf> {
+        self.file.flush()?;
+        fs::rename(&self.temp, &self.path)?;
+        self.finished = true;
+        Ok(std::mem::take(&mut self.path))
+    }
+}
+
+/// Moves a finished upload from `from` to `to`, copying it if they are on
+/// different file systems.
+pub(crate) fn persist(from: &Path, to: &Path) -> std::io::Result<()> {
+    match fs::rename(from, to) {
+        Err(e) if e.kind() == std::io::ErrorKind::CrossesDevices => {
+            fs::copy(from, to)?;
+            fs::remove_file(from)
+        }
+        result => result,
+    }
+}
+
+impl Drop for Upload {
+    fn drop(&mut self) {
+        if !self.finished {
+            let _ = fs::remove_file(&self.temp);
+        }
+    }
+}
@@ -0,0 +1,411 @@
+use std::collections::HashMap;
+use std::os::fd::RawFd;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicU32, Ordering};
+
+use crate::backend::Backend;
+use crate::reactor::{State, Token};
+use crate::syscall;
+
+const IORING_OFF_SQ_RING: i64 = 0;
+const IORING_OFF_CQ_RING: i64 = 0x800_0000;
+const IORING_OFF_SQES: i64 = 0x1000_0000;
+const IORING_FEAT_SINGLE_MMAP: u32 = 1;
+const IORING_ENTER_GETEVENTS: u32 = 1;
+const IORING_OP_POLL_ADD: u8 = 6;
+const IORING_OP_POLL_REMOVE: u8 = 7;
+const IORING_OP_TIMEOUT: u8 = 11;
+
+/// `user_data` of the timeout bounding a wait.
+const TIMEOUT_DATA: u64 = u64::MAX;
+/// `user_data` of poll cancellations.
+const REMOVE_DATA: u64 = u64::MAX - 1;
+
+#[repr(C)]
+#[derive(Default)]
+struct SqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    flags: u32,
+    dropped: u32,
+    array: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct CqringOffsets {
+    head: u32,
+    tail: u32,
+    ring_mask: u32,
+    ring_entries: u32,
+    overflow: u32,
+    cqes: u32,
+    flags: u32,
+    resv1: u32,
+    user_addr: u64,
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct Params {
+    sq_entries: u32,
+    cq_entries: u32,
+    flags: u32,
+    sq_thread_cpu: u32,
+    sq_thread_idle: u32,
+    features: u32,
+    wq_fd: u32,
+    resv: [u32; 3],
+    sq_off: SqringOffsets,
+    cq_off: CqringOffsets,
+}
+
+/// Submission queue entry; `op_flags` holds the poll mask for `POLL_ADD`.
+#[repr(C)]
+#[derive(Default)]
+struct Sqe {
+    opcode: u8,
+    flags: u8,
+    ioprio: u16,
+    fd: i32,
+    off: u64,
+    addr: u64,
+    len: u32,
+    op_flags: u32,
+    user_data: u64,
+    buf_index: u16,
+    personality: u16,
+    splice_fd_in: i32,
+    addr3: u64,
+    pad: u64,
+}
+
+#[repr(C)]
+struct Cqe {
+    user_data: u64,
+    res: i32,
+    flags: u32,
+}
+
+struct Mapping {
+    ptr: *mut c_void,
+    len: usize,
+}
+
+impl Mapping {
+    fn new(fd: RawFd, len: usize, offset: i64) -> std::io::Result<Self> {
+        let ptr = unsafe {
+            libc::mmap(
+                std::ptr::null_mut(),
+                len,
+                libc::PROT_READ | libc::PROT_WRITE,
+                libc::MAP_SHARED | libc::MAP_POPULATE,
+                fd,
+                offset,
+            )
+        };
+        if ptr == libc::MAP_FAILED {
+            return Err(std::io::Error::last_os_error());
+        }
+        Ok(Self { ptr, len })
+    }
+
+    /// Pointer to the value at byte `offset` into the mapping.
+    fn at<T>(&self, offset: u32) -> *mut T {
+        unsafe { self.ptr.cast::<u8>().add(offset as usize).cast::<T>() }
+    }
+}
+
+impl Drop for Mapping {
+    fn drop(&mut self) {
+        let _ = unsafe { libc::munmap(self.ptr, self.len) };
+    }
+}
+
+/// Completion-based backend: each interest is a one-shot `POLL_ADD`
+/// request on an `io_uring` instance, and its completion is the readiness
+/// event. Rings are driven with raw `io_uring_setup` and `io_uring_enter`
+/// calls.
+pub(crate) struct IoUring {
+    fd: RawFd,
+    sq_head: *const AtomicU32,
+    sq_tail: *const AtomicU32,
+    sq_mask: u32,
+    sq_entries: u32,
+    sq_array: *mut u32,
+    sqes: *mut Sqe,
+    cq_head: *const AtomicU32,
+    cq_tail: *const AtomicU32,
+    cq_mask: u32,
+    cqes: *const Cqe,
+    /// Entries pushed but not yet submitted.
+    queued: u32,
+    /// Poll request currently armed for each registration.
+    armed: HashMap<Token, u64>,
+    /// Registration of each outstanding poll request; a completion whose
+    /// request was replaced or cancelled is not found here and is dropped.
+    polls: HashMap<u64, Token>,
+    next_poll: u64,
+    /// Read by the kernel when a timeout entry is submitted.
+    timeout: libc::timespec,
+    // unmapped after the pointers above are gone
+    _rings: Vec<Mapping>,
+}
+
+impl IoUring {
+    pub(crate) fn new(entries: u32) -> std::io::Result<Self> {
+        let mut params = Params::default();
+        #[allow(clippy::cast_possible_truncation)]
+        // the kernel opens it close-on-exec
+        let fd = syscall!(syscall(libc::SYS_io_uring_setup, entries, &raw mut params))? as RawFd;
+        match Self::map(fd, &params) {
+            Ok(ring) => Ok(ring),
+            Err(e) => {
+                let _ = unsafe { libc::close(fd) };
+                Err(e)
+            }
+        }
+    }
+
+    fn map(fd: RawFd, params: &Params) -> std::io::Result<Self> {
+        let sq_len = params.sq_off.array as usize + params.sq_entries as usize * size_of::<u32>();
+        let cq_len = params.cq_off.cqes as usize + params.cq_entries as usize * size_of::<Cqe>();
+        let mut rings = Vec::with_capacity(3);
+        if params.features & IORING_FEAT_SINGLE_MMAP != 0 {
+            rings.push(Mapping::new(fd, sq_len.max(cq_len), IORING_OFF_SQ_RING)?);
+        } else {
+            rings.push(Mapping::new(fd, sq_len, IORING_OFF_SQ_RING)?);
+            rings.push(Mapping::new(fd, cq_len, IORING_OFF_CQ_RING)?);
+        }
+        rings.push(Mapping::new(
+            fd,
+            params.sq_entries as usize * size_of::<Sqe>(),
+            IORING_OFF_SQES,
+        )?);
+        let (sq, cq, sqes) = (&rings[0], &rings[rings.len() - 2], &rings[rings.len() - 1]);
+        Ok(Self {
+            fd,
+            sq_head: sq.at(params.sq_off.head),
+            sq_tail: sq.at(params.sq_off.tail),
+            sq_mask: unsafe { *sq.at::<u32>(params.sq_off.ring_mask) },
+            sq_entries: params.sq_entries,
+            sq_array: sq.at(params.sq_off.array),
+            sqes: sqes.at(0),
+            cq_head: cq.at(params.cq_off.head),
+            cq_tail: cq.at(params.cq_off.tail),
+            cq_mask: unsafe { *cq.at::<u32>(params.cq_off.ring_mask) },
+            cqes: cq.at(params.cq_off.cqes),
+            queued: 0,
+            armed: HashMap::new(),
+            polls: HashMap::new(),
+            next_poll: 0,
+            timeout: libc::timespec {
+                tv_sec: 0,
+                tv_nsec: 0,
+            },
+            _rings: rings,
+        })
+    }
+
+    fn push(&mut self, sqe: Sqe) -> std::io::Result<()> {
+        let tail = unsafe { (*self.sq_tail).load(Ordering::Relaxed) };
+        let head = unsafe { (*self.sq_head).load(Ordering::Acquire) };
+        if tail.wrapping_sub(head) == self.sq_entries {
+            // the kernel takes no entries while the completion queue is
+            // full, and one written over before it is taken corrupts the ring
+            self.submit(0, 0)?;
+            let head = unsafe { (*self.sq_head).load(Ordering::Acquire) };
+            if tail.wrapping_sub(head) == self.sq_entries {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::ResourceBusy,
+                    "io_uring submission queue is full",
+                ));
+            }
+        }
+        let index = tail & self.sq_mask;
+        unsafe {
+            self.sqes.add(index as usize).write(sqe);
+            self.sq_array.add(index as usize).write(index);
+            (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+        }
+        self.queued += 1;
+        Ok(())
+    }
+
+    /// Submits the queued entries and, with `IORING_ENTER_GETEVENTS`,
+    /// waits for `min_complete` completions.
+    fn enter(&mut self, min_complete: u32, flags: u32) -> std::io::Result<()> {
+        match self.submit(min_complete, flags) {
+            // a signal or a full completion queue cuts the wait short
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    std::io::ErrorKind::Interrupted
+                        | std::io::ErrorKind::ResourceBusy
+                        | std::io::ErrorKind::WouldBlock
+                ) =>
+            {
+                Ok(())
+            }
+            result => result,
+        }
+    }
+
+    /// Like [`IoUring::enter`], but fails if the kernel takes no entries.
+    fn submit(&mut self, min_complete: u32, flags: u32) -> std::io::Result<()> {
+        let submitted = syscall!(syscall(
+            libc::SYS_io_uring_enter,
+            self.fd,
+            self.queued,
+            min_complete,
+            flags,
+            std::ptr::null::<c_void>(),
+            0
+        ))?;
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let submitted = submitted as u32;
+        self.queued -= submitted.min(self.queued);
+        Ok(())
+    }
+
+    fn arm(&mut self, fd: RawFd, events: u32, token: Token) -> std::io::Result<()> {
+        self.cancel(token)?;
+        self.next_poll += 1;
+        let id = self.next_poll;
+        self.armed.insert(token, id);
+        self.polls.insert(id, token);
+        // POLL_ADD is one-shot unless asked otherwise, like EPOLLONESHOT
+        self.push(Sqe {
+            opcode: IORING_OP_POLL_ADD,
+            fd,
+            op_flags: events,
+            user_data: id,
+            ..Sqe::default()
+        })
+    }
+
+    fn cancel(&mut self, token: Token) -> std::io::Result<()> {
+        if let Some(id) = self.armed.remove(&token) {
+            self.polls.remove(&id);
+            self.push(Sqe {
+                opcode: IORING_OP_POLL_REMOVE,
+                fd: -1,
+                addr: id,
+                user_data: REMOVE_DATA,
+                ..Sqe::default()
+            })?;
+        }
+        Ok(())
+    }
+
+    fn has_completions(&self) -> bool {
+        unsafe {
+            (*self.cq_head).load(Ordering::Relaxed) != (*self.cq_tail).load(Ordering::Acquire)
+        }
+    }
+}
+
+impl Backend for IoUring {
+    fn add(&mut self, fd: RawFd, events: u32, token: Token) -> std::io::Result<()> {
+        self.arm(fd, events, token)
+    }
+
+    fn modify(&mut self, fd: RawFd, events: u32, token: Token) -> std::io::Result<()> {
+        self.arm(fd, events, token)
+    }
+
+    fn remove(&mut self, _fd: RawFd, token: Token) -> std::io::Result<()> {
+        self.cancel(token)?;
+        // a pending poll holds on to the file, which would stay open
+        // after the fd is closed
+        self.enter(0, 0)
+    }
+
+    fn wait(&mut self, events: &mut Vec<(Token, State)>, timeout: i32) -> std::io::Result<()> {
+        if self.has_completions() || timeout == 0 {
+            self.enter(0, 0)?;
+        } else {
+            if timeout > 0 {
+                self.timeout = libc::timespec {
+                    tv_sec: (timeout / 1000).into(),
+                    tv_nsec: (timeout % 1000 * 1_000_000).into(),
+                };
+                // ends early once anything else completes
+                self.push(Sqe {
+                    opcode: IORING_OP_TIMEOUT,
+                    fd: -1,
+                    off: 1,
+                    addr: (&raw const self.timeout) as u64,
+                    len: 1,
+                    user_data: TIMEOUT_DATA,
+                    ..Sqe::default()
+                })?;
+            }
+            self.enter(1, IORING_ENTER_GETEVENTS)?;
+        }
+
+        let mut head = unsafe { (*self.cq_head).load(Ordering::Relaxed) };
+        let tail = unsafe { (*self.cq_tail).load(Ordering::Acquire) };
+        while head != tail {
+            let cqe = unsafe { &*self.cqes.add((head & self.cq_mask) as usize) };
+            head = head.wrapping_add(1);
+            let Some(token) = self.polls.remove(&cqe.user_data) else {
+                continue;
+            };
+            self.armed.remove(&token);
+            #[allow(clippy::cast_sign_loss)]
+            let ready = if cqe.res < 0 {
+                libc::EPOLLERR as u32
+            } else {
+                cqe.res as u32
+            };
+            events.push((token, State::new(ready)));
+        }
+        unsafe { (*self.cq_head).store(head, Ordering::Release) };
+        Ok(())
+    }
+}
+
+impl Drop for IoUring {
+    fn drop(&mut self) {
+        let _ = unsafe { libc::close(self.fd) };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn more_entries_than_the_ring_holds() {
+        let Ok(mut ring) = IoUring::new(4) else {
+            // not available here
+            return;
+        };
+        // readable right away, so that every poll completes
+        let fd = syscall!(eventfd(1, libc::EFD_CLOEXEC)).unwrap();
+        let mut armed = Vec::new();
+        for index in 0..64 {
+            let token = Token::from_u64(index);
+            match ring.add(fd, libc::EPOLLIN as u32, token) {
+                Ok(()) => armed.push(token),
+                Err(e) => assert_eq!(e.kind(), std::io::ErrorKind::ResourceBusy),
+            }
+        }
+        let mut seen = Vec::new();
+        let mut events = Vec::new();
+        while seen.len() < armed.len() {
+            ring.wait(&mut events, 1000).unwrap();
+            assert!(!events.is_empty(), "polls went missing");
+            for (token, _) in events.drain(..) {
+                assert!(armed.contains(&token) && !seen.contains(&token));
+                seen.push(token);
+            }
+        }
+        let _ = unsafe { libc::close(fd) };
+    }
+}